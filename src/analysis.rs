@@ -0,0 +1,324 @@
+//! Static analysis of compiled state machines.
+//!
+//! [analyse] looks over the transitions of a [StateMachine] for states and branches that can
+//! never be run. Its findings are warnings: the machine still works, but probably not as
+//! intended. They are included in the diagnostics from [crate::compile_with_diagnostics].
+//! ```
+//! use shakemyleg::{compile, analysis::analyse};
+//!
+//! let src = r#"
+//! state A:
+//!   always:
+//!     end
+//! state B:
+//!   always:
+//!     changeto A
+//! "#;
+//!
+//! let sm = compile(src).unwrap();
+//! let warnings = analyse(&sm, src);
+//! assert_eq!(warnings[0].message, "State B is unreachable from the initial state A.");
+//! ```
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::diagnostic::Diagnostic;
+use crate::expression::Expression;
+use crate::state::{Branch, State, StateOp};
+use crate::StateMachine;
+
+
+/// Why a branch can never run.
+enum DeadBranch {
+    /// An earlier branch (given by line) is always taken.
+    Shadowed(usize),
+    AlwaysFalse,
+}
+
+/// Value of a condition, if it doesn't depend on anything.
+fn constant_condition(condition: &Expression) -> Option<bool> {
+    if !condition.is_constant() {
+        return None;
+    }
+
+    let mut o = json::object! { };
+    let mut g = json::object! { };
+    let mut l = json::object! { };
+    condition.evaluate(&json::object! { }, &mut o, &mut g, &mut l).ok().map(|v| v.as_bool())
+}
+
+/// Pair each branch of a state with the reason it can't run, if any. The default branch is
+/// always live, as it can be run by `advance` regardless of its condition.
+fn classify_branches(state: &State) -> Vec<(&Branch, Option<DeadBranch>)> {
+    let mut always_taken: Option<usize> = None;
+    state.branches().iter()
+        .enumerate()
+        .map(|(i, branch)| {
+            let constant = constant_condition(&branch.condition);
+            let dead = if state.default_branch() == Some(i) {
+                None
+            }
+            else if let Some(line) = always_taken {
+                Some(DeadBranch::Shadowed(line))
+            }
+            else if constant == Some(false) {
+                Some(DeadBranch::AlwaysFalse)
+            }
+            else {
+                None
+            };
+
+            if constant == Some(true) && always_taken.is_none() {
+                always_taken = Some(branch.line);
+            }
+            (branch, dead)
+        })
+        .collect()
+}
+
+
+/// The transitions between the states of a machine, counting only branches which can run.
+#[derive(Clone, Debug)]
+pub struct TransitionGraph {
+    /// The initial state of each region (just one, unless the machine has `parallel` regions).
+    pub initial: Vec<String>,
+
+    /// For each state, the states it can change to.
+    pub edges: BTreeMap<String, BTreeSet<String>>,
+
+    /// States which can end the machine.
+    pub ends: BTreeSet<String>,
+}
+
+impl TransitionGraph {
+    pub fn new(sm: &StateMachine) -> Self {
+        let mut edges = BTreeMap::new();
+        let mut ends = BTreeSet::new();
+        for (name, state) in sm.states() {
+            let targets: &mut BTreeSet<String> = edges.entry(name.clone()).or_default();
+            for (branch, dead) in classify_branches(state) {
+                if dead.is_some() {
+                    continue;
+                }
+                match &branch.state_op {
+                    StateOp::ChangeTo(transition) => { targets.insert(transition.target.clone()); },
+                    StateOp::End => { ends.insert(name.clone()); },
+                    StateOp::Stay => (),
+                }
+            }
+        }
+
+        let initial = sm.initial_states().into_iter().cloned().collect();
+        Self { initial, edges, ends }
+    }
+
+    /// States which can be reached from the initial states.
+    pub fn reachable(&self) -> BTreeSet<String> {
+        let mut seen: BTreeSet<String> = self.initial.iter().cloned().collect();
+        let mut stack = self.initial.clone();
+        while let Some(name) = stack.pop() {
+            for target in self.edges.get(&name).into_iter().flatten() {
+                if seen.insert(target.clone()) {
+                    stack.push(target.clone());
+                }
+            }
+        }
+        seen
+    }
+
+    /// States from which the machine can reach `end`.
+    pub fn can_end(&self) -> BTreeSet<String> {
+        let mut can_end = self.ends.clone();
+        loop {
+            let before = can_end.len();
+            for (name, targets) in &self.edges {
+                if targets.iter().any(|t| can_end.contains(t)) {
+                    can_end.insert(name.clone());
+                }
+            }
+            if can_end.len() == before {
+                break can_end;
+            }
+        }
+    }
+}
+
+
+/// Look for states and branches of `sm` that can never run. `src` must be the source `sm` was
+/// compiled from.
+///
+/// Reports (as warnings):
+/// - states that are unreachable from the initial state,
+/// - states that can never reach `end` (only if the machine, or the state's `parallel` region,
+///   ends somewhere, as one with no `end` at all is taken to run forever on purpose),
+/// - branches that come after a branch that always runs (`always`, `normally`, `when true`),
+/// - branches with a condition that is always false.
+pub fn analyse(sm: &StateMachine, src: &str) -> Vec<Diagnostic> {
+    let graph = TransitionGraph::new(sm);
+    let reachable = graph.reachable();
+    let can_end = graph.can_end();
+
+    let mut states: Vec<_> = sm.states().values().collect();
+    states.sort_by_key(|state| state.span().start);
+
+    let mut diagnostics = Vec::new();
+
+    // Branches inherited by nested states are in each of them, but only reported once.
+    let mut reported = BTreeSet::new();
+    for state in states {
+        let name = state.name();
+        if !reachable.contains(name) {
+            diagnostics.push(Diagnostic::warning(src, state.span(), format!("State {name} is unreachable from the initial state {}.", sm.initial_state_of(name)))
+                .with_hint("no branch that can run changes to this state"));
+        }
+        else if graph.ends.iter().any(|end| sm.initial_state_of(end) == sm.initial_state_of(name)) && !can_end.contains(name) {
+            diagnostics.push(Diagnostic::warning(src, state.span(), format!("State {name} can never reach `end`."))
+                .with_hint("every path from this state loops forever"));
+        }
+
+        for (branch, dead) in classify_branches(state) {
+            if dead.is_some() && !reported.insert(branch.span.start) {
+                continue;
+            }
+            match dead {
+                Some(DeadBranch::Shadowed(line)) => {
+                    diagnostics.push(Diagnostic::warning(src, branch.span.clone(), format!("Branch can never run: the branch on line {line} always runs first."))
+                        .with_hint("remove this branch, or make the earlier condition more specific"));
+                },
+                Some(DeadBranch::AlwaysFalse) => {
+                    diagnostics.push(Diagnostic::warning(src, branch.span.clone(), "Condition is always false, so this branch can never run."));
+                },
+                None => (),
+            }
+        }
+    }
+
+    diagnostics
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compile;
+
+    fn messages(src: &str) -> Vec<String> {
+        let sm = compile(src).unwrap();
+        analyse(&sm, src).into_iter().map(|d| d.message).collect()
+    }
+
+    #[test]
+    fn test_clean_machine() {
+        const SRC: &str = r#"
+state A:
+    when inputs.x > 1:
+        changeto B
+state B:
+    when inputs.x > 2:
+        end
+    otherwise:
+        changeto A
+"#;
+        assert!(messages(SRC).is_empty());
+    }
+
+    #[test]
+    fn test_unreachable_and_trapped() {
+        const SRC: &str = r#"
+state A:
+    when inputs.x > 1:
+        changeto B
+    when inputs.x < 0:
+        changeto C
+state B:
+    always:
+        end
+state C:
+    always:
+        changeto C
+state D:
+    always:
+        changeto A
+"#;
+        assert_eq!(messages(SRC), vec![
+            "State C can never reach `end`.",
+            "State D is unreachable from the initial state A.",
+        ]);
+    }
+
+    #[test]
+    fn test_no_end_is_fine() {
+        const SRC: &str = r#"
+state A:
+    always:
+        changeto B
+state B:
+    always:
+        changeto A
+"#;
+        assert!(messages(SRC).is_empty());
+    }
+
+    #[test]
+    fn test_dead_branches() {
+        const SRC: &str = r#"
+state A:
+    when false:
+        changeto B
+    when 1 > 2:
+        outputs.x = 1
+    when true:
+        outputs.x = 2
+    when inputs.x > 1:
+        changeto B
+    otherwise:
+        default
+        changeto B
+state B:
+    always:
+        changeto A
+"#;
+        assert_eq!(messages(SRC), vec![
+            "Condition is always false, so this branch can never run.",
+            "Condition is always false, so this branch can never run.",
+            "Branch can never run: the branch on line 7 always runs first.",
+        ]);
+    }
+
+    #[test]
+    fn test_inherited_branches_reported_once() {
+        const SRC: &str = r#"
+state A:
+    when false:
+        end
+    state B:
+        otherwise:
+            changeto C
+    state C:
+        otherwise:
+            changeto B
+"#;
+        assert_eq!(messages(SRC), vec!["Condition is always false, so this branch can never run."]);
+    }
+
+    #[test]
+    fn test_parallel_regions() {
+        const SRC: &str = r#"
+parallel:
+    region A:
+        state A1:
+            when inputs.x > 1:
+                end
+    region B:
+        state B1:
+            always:
+                changeto B2
+        state B2:
+            always:
+                changeto B1
+        state B3:
+            always:
+                stay
+"#;
+        assert_eq!(messages(SRC), vec!["State B.B3 is unreachable from the initial state B.B1."]);
+    }
+}
@@ -0,0 +1,115 @@
+use crate::expression::Expression;
+use crate::lexer::Span;
+
+
+/// Syntax tree of an SML source file, as produced by the parser and consumed by the compiler.
+#[derive(Clone, Debug)]
+pub struct Program {
+    pub items: Vec<Item>,
+}
+
+#[derive(Clone, Debug)]
+pub enum Item {
+    /// Lines of the block, and the span of its header.
+    DefaultHead(Vec<Line>, Span),
+    State(StateDecl),
+
+    /// Regions of a `parallel` block, and the span of its header.
+    Parallel(Vec<RegionDecl>, Span),
+
+    /// Placeholder for something that failed to parse and was skipped.
+    Error,
+}
+
+/// `region <name>:` in a `parallel` block: states which run independently of other regions'.
+#[derive(Clone, Debug)]
+pub struct RegionDecl {
+    pub name: String,
+    pub span: Span,
+
+    /// `None` for a line that failed to parse and was skipped.
+    pub states: Vec<Option<StateDecl>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct StateDecl {
+    pub name: String,
+    pub span: Span,
+    pub items: Vec<StateItem>,
+}
+
+#[derive(Clone, Debug)]
+pub enum StateItem {
+    /// Lines of the block, and the span of its header.
+    Head(Vec<Line>, Span),
+
+    /// `on enter:`, run when the state is entered. Lines of the block, and the span of its header.
+    OnEnter(Vec<Line>, Span),
+
+    /// `on exit:`, run when the state is left. Lines of the block, and the span of its header.
+    OnExit(Vec<Line>, Span),
+    Branch(BranchDecl),
+
+    /// A nested state, which inherits the head and branches of this one.
+    State(StateDecl),
+    Error,
+}
+
+#[derive(Clone, Debug)]
+pub struct BranchDecl {
+    pub kind: BranchKind,
+    pub span: Span,
+    pub lines: Vec<Line>,
+}
+
+#[derive(Clone, Debug)]
+pub enum BranchKind {
+    When(Expression),
+    Always,
+    Normally,
+    Otherwise,
+}
+
+/// A statement or state operation. Its span doesn't include the line break.
+#[derive(Clone, Debug)]
+pub struct Line {
+    pub kind: LineKind,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug)]
+pub enum LineKind {
+    Expr(Expression),
+
+    /// `if`, then any `elif`s, then maybe an `else`.
+    If(Vec<IfArm>),
+    For(ForLoop),
+
+    /// Target state name, its span, and any actions given with `with`.
+    ChangeTo(String, Span, Vec<Line>),
+    End,
+    Stay,
+    Default,
+    Error,
+}
+
+/// One arm of an `if` statement: the `if` or an `elif` with its condition, or the `else`.
+#[derive(Clone, Debug)]
+pub struct IfArm {
+    pub condition: Option<Expression>,
+
+    /// Span of the header, without its colon.
+    pub span: Span,
+    pub lines: Vec<Line>,
+}
+
+/// `for target in list:` and its block.
+#[derive(Clone, Debug)]
+pub struct ForLoop {
+    pub target: Expression,
+    pub list: Expression,
+
+    /// Span of the header, without its colon.
+    pub span: Span,
+    pub lines: Vec<Line>,
+}
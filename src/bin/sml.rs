@@ -0,0 +1,225 @@
+//! `sml`: check, run, format and draw SML state machines from the command line.
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read};
+use std::process::ExitCode;
+
+use serde_json::{json, Value};
+
+use shakemyleg::{compile_with_diagnostics, format, SML_Error, StateMachine};
+
+
+const USAGE: &str = "\
+Usage: sml <command> [options] <file.sml>
+
+Commands:
+    check <file>                       Compile a machine and print any errors or warnings.
+    run <file> [--inputs <file>]       Run a machine on JSON inputs, one object per line (read
+        [--advance]                    from stdin if no file is given). Prints the outputs,
+                                       current state (of each region, for a machine with
+                                       `parallel` regions) and globals after each input as JSON.
+                                       With --advance, run only the default branch of each state.
+    graph <file> [--mermaid]           Print a diagram of a machine, as Graphviz DOT or Mermaid.
+    fmt <file> [--write | --check]     Print a machine in canonical layout, or rewrite it in place.
+                                       With --check, print nothing and fail if it isn't formatted.
+";
+
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Check { file: String },
+    Run { file: String, inputs: Option<String>, advance: bool },
+    Graph { file: String, mermaid: bool },
+    Fmt { file: String, write: bool, check: bool },
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+    let command = args.next().ok_or("no command given")?;
+
+    let mut file = None;
+    let mut inputs = None;
+    let mut flags = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--inputs" => { inputs = Some(args.next().ok_or("--inputs needs a file")?); },
+            flag if flag.starts_with("--") => flags.push(arg),
+            _ if file.is_none() => { file = Some(arg); },
+            _ => { return Err(format!("unexpected argument {arg:?}")); },
+        }
+    }
+    let file = file.ok_or("no file given")?;
+
+    if inputs.is_some() && command != "run" {
+        return Err("--inputs is only used by `run`".to_string());
+    }
+
+    let mut flag = |name: &str| match flags.iter().position(|f| f == name) {
+        Some(i) => { flags.remove(i); true },
+        None => false,
+    };
+    let command = match command.as_str() {
+        "check" => Command::Check { file },
+        "run" => Command::Run { file, inputs, advance: flag("--advance") },
+        "graph" => Command::Graph { file, mermaid: flag("--mermaid") },
+        "fmt" => Command::Fmt { file, write: flag("--write"), check: flag("--check") },
+        c => { return Err(format!("unknown command {c:?}")); },
+    };
+
+    if let Some(unknown) = flags.first() {
+        return Err(format!("unknown option {unknown:?}"));
+    }
+    Ok(command)
+}
+
+
+/// Compile a file, printing diagnostics. Returns `None` if there were errors.
+fn load(file: &str) -> Result<Option<StateMachine>, String> {
+    let src = fs::read_to_string(file).map_err(|e| format!("could not read {file}: {e}"))?;
+    let (sm, diagnostics) = compile_with_diagnostics(&src);
+    for diagnostic in diagnostics {
+        eprintln!("{}\n", diagnostic.with_file(file));
+    }
+    Ok(sm)
+}
+
+fn check(file: &str) -> Result<ExitCode, String> {
+    match load(file)? {
+        Some(_) => {
+            eprintln!("{file}: ok");
+            Ok(ExitCode::SUCCESS)
+        },
+        None => Ok(ExitCode::FAILURE),
+    }
+}
+
+fn run(file: &str, inputs: Option<&str>, advance: bool) -> Result<ExitCode, String> {
+    let Some(mut sm) = load(file)? else {
+        return Ok(ExitCode::FAILURE);
+    };
+
+    let reader: Box<dyn Read> = match inputs {
+        Some(path) => Box::new(fs::File::open(path).map_err(|e| format!("could not read {path}: {e}"))?),
+        None => Box::new(io::stdin()),
+    };
+
+    for (lineno, line) in BufReader::new(reader).lines().enumerate() {
+        let line = line.map_err(|e| format!("could not read inputs: {e}"))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let i: Value = serde_json::from_str(&line)
+            .map_err(|e| format!("inputs line {}: {e}", lineno + 1))?;
+
+        let o: Option<Value> = if advance { sm.advance(i) } else { sm.run(i) }
+            .map_err(|e| format!("inputs line {}: {e}", lineno + 1))?;
+        let Some(o) = o else {
+            eprintln!("machine ended before inputs line {}", lineno + 1);
+            break;
+        };
+        let g: Value = sm.globals().map_err(|e| e.to_string())?;
+        let states = sm.current_states();
+        if states.len() > 1 {
+            println!("{}", json!({ "outputs": o, "states": states, "globals": g }));
+        }
+        else {
+            println!("{}", json!({ "outputs": o, "state": sm.current_state(), "globals": g }));
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn graph(file: &str, mermaid: bool) -> Result<ExitCode, String> {
+    let Some(sm) = load(file)? else {
+        return Ok(ExitCode::FAILURE);
+    };
+    print!("{}", if mermaid { sm.to_mermaid() } else { sm.to_dot() });
+    Ok(ExitCode::SUCCESS)
+}
+
+fn fmt(file: &str, write: bool, check: bool) -> Result<ExitCode, String> {
+    let src = fs::read_to_string(file).map_err(|e| format!("could not read {file}: {e}"))?;
+    let formatted = match format(&src) {
+        Ok(formatted) => formatted,
+        Err(SML_Error::SyntaxError(diagnostic)) => {
+            eprintln!("{}\n", diagnostic.with_file(file));
+            return Ok(ExitCode::FAILURE);
+        },
+        Err(e) => { return Err(e.to_string()); },
+    };
+
+    if check {
+        if formatted != src {
+            eprintln!("{file}: not formatted");
+            return Ok(ExitCode::FAILURE);
+        }
+    }
+    else if write {
+        if formatted != src {
+            fs::write(file, formatted).map_err(|e| format!("could not write {file}: {e}"))?;
+        }
+    }
+    else {
+        print!("{formatted}");
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+
+fn main() -> ExitCode {
+    let command = match parse_args(std::env::args().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("error: {e}\n\n{USAGE}");
+            return ExitCode::from(2);
+        },
+    };
+
+    let result = match &command {
+        Command::Check { file } => check(file),
+        Command::Run { file, inputs, advance } => run(file, inputs.as_deref(), *advance),
+        Command::Graph { file, mermaid } => graph(file, *mermaid),
+        Command::Fmt { file, write, check } => {
+            if *write && *check {
+                eprintln!("error: --write and --check can't be used together\n\n{USAGE}");
+                return ExitCode::from(2);
+            }
+            fmt(file, *write, *check)
+        },
+    };
+
+    result.unwrap_or_else(|e| {
+        eprintln!("error: {e}");
+        ExitCode::FAILURE
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Result<Command, String> {
+        parse_args(s.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(args("check a.sml"), Ok(Command::Check { file: "a.sml".to_string() }));
+        assert_eq!(
+            args("run --advance a.sml --inputs i.jsonl"),
+            Ok(Command::Run { file: "a.sml".to_string(), inputs: Some("i.jsonl".to_string()), advance: true }),
+        );
+        assert_eq!(args("graph a.sml --mermaid"), Ok(Command::Graph { file: "a.sml".to_string(), mermaid: true }));
+        assert_eq!(args("fmt --check a.sml"), Ok(Command::Fmt { file: "a.sml".to_string(), write: false, check: true }));
+    }
+
+    #[test]
+    fn test_parse_args_errors() {
+        assert!(args("").is_err());
+        assert!(args("check").is_err());
+        assert!(args("frobnicate a.sml").is_err());
+        assert!(args("check a.sml b.sml").is_err());
+        assert!(args("check a.sml --mermaid").is_err());
+        assert!(args("graph a.sml --inputs i.jsonl").is_err());
+    }
+}
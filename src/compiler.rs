@@ -1,42 +1,79 @@
 use std::collections::HashMap;
 
+use std::path::Path;
+
+use crate::analysis::analyse;
+use crate::diagnostic::{Diagnostic, SourceSpan};
 use crate::error::{SML_Error, SML_Result};
 use crate::expression::Expression;
-use crate::state::{State, StateOp};
+use crate::function::Registry;
+use crate::state::{Branch, State, Statement, StatementKind, StateOp, Transition};
 use crate::value::Value;
 use crate::StateMachine;
-use crate::parse_expression::expr_from_str;
-
-
-enum CompileState {
-    TopLevel, // "state <name>:" or "default head:"
-    State,
-    StateHead,
-    StateBranch,
-    DefaultHead,
-}
+use crate::ast::{Program, Item, StateDecl, StateItem, BranchDecl, BranchKind, Line, LineKind};
+use crate::lexer::{lex, ParseError, Span};
+use crate::parser::parse;
 
 
 struct StateData {
+    /// Full path of the state, like `Running.Heating`.
     pub name: String,
-    pub head: Vec<Expression>,
+    pub span: Span,
+
+    /// Indices (in the list of all states) of the state this is nested in, and of those nested
+    /// in it, in order. A state with children is composite: the machine is never in it directly.
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+
+    /// Index of the `parallel` region the state is in, counting from 1; 0 is outside `parallel`.
+    pub region: usize,
+    pub head: Vec<Statement>,
+    pub on_enter: Vec<Statement>,
+    pub on_exit: Vec<Statement>,
     pub branches: Vec<StateBranchData>,
     pub has_default: bool,
     pub has_otherwise: bool,
     pub has_always: bool,
+
+    /// Number of branches inherited from the states this is nested in, which come before its own
+    /// once inherited, so count towards the checks on branch order.
+    pub inherited_branches: usize,
+
+    /// Set if a branch failed to parse, so that checks on branch order don't pile on.
+    pub has_errors: bool,
 }
 
 impl StateData {
-    fn new(name: String) -> Self {
+    fn new(name: String, span: Span, parent: Option<usize>, region: usize) -> Self {
         Self {
             name,
+            span,
+            parent,
+            children: Vec::new(),
+            region,
             head: Vec::new(),
+            on_enter: Vec::new(),
+            on_exit: Vec::new(),
             branches: Vec::new(),
             has_default: false,
             has_otherwise: false,
             has_always: false,
+            inherited_branches: 0,
+            has_errors: false,
         }
     }
+
+    /// Take on the head and branches of `parent`, which run before this state's own. A default
+    /// branch of this state's own overrides an inherited one.
+    fn inherit(&mut self, parent: &StateData) {
+        self.head.splice(0..0, parent.head.iter().cloned());
+        let mut inherited: Vec<_> = parent.branches.clone();
+        if self.has_default {
+            inherited.iter_mut().for_each(|b| b.is_default = false);
+        }
+        self.branches.splice(0..0, inherited);
+        self.has_default |= parent.has_default;
+    }
 }
 
 impl TryFrom<StateData> for State {
@@ -65,8 +102,8 @@ impl TryFrom<StateData> for State {
         else {
             None
         };
-        let body = state_data.branches.into_iter().map(|b| (b.condition, b.body, b.state_op)).collect();
-        let mut rv = State::new(name, head, body);
+        let body = state_data.branches.into_iter().map(|b| b.branch).collect();
+        let mut rv = State::new(name, state_data.span, head, state_data.on_enter, state_data.on_exit, body);
         if let Some(idx) = default_branch {
             rv.set_default(idx)?;
         }
@@ -74,25 +111,474 @@ impl TryFrom<StateData> for State {
     }
 }
 
+#[derive(Clone)]
 struct StateBranchData {
-    condition: Expression,
-    body: Vec<Expression>,
-    state_op: StateOp,
+    branch: Branch,
     is_default: bool,
 }
 
 impl StateBranchData {
-    fn new(condition: Expression) -> Self {
+    fn new(condition: Expression, label: String, line: usize, span: Span) -> Self {
         Self {
-            condition,
-            body: Vec::new(),
-            state_op: StateOp::Stay,
+            branch: Branch { condition, body: Vec::new(), state_op: StateOp::Stay, label, line, span },
             is_default: false,
         }
     }
 }
 
 
+/// Index of the state the machine goes to on changing to `states[index]`: the state itself, or
+/// for a composite state, the first state nested in it (repeatedly).
+fn first_leaf(states: &[StateData], mut index: usize) -> usize {
+    while let Some(&first) = states[index].children.first() {
+        index = first;
+    }
+    index
+}
+
+
+/// Levenshtein distance between two strings, for suggesting corrections.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+
+/// Lowers a syntax tree into states, collecting diagnostics as it goes rather than stopping at
+/// the first problem.
+struct Lowering<'a> {
+    src: &'a str,
+    diagnostics: Vec<Diagnostic>,
+
+    /// Every `changeto` target seen, with the path of the state it is in, to be resolved once all
+    /// states are known.
+    transitions: Vec<(String, String, Span)>,
+}
+
+impl<'a> Lowering<'a> {
+    fn new(src: &'a str) -> Self {
+        Self { src, diagnostics: Vec::new(), transitions: Vec::new() }
+    }
+
+    fn report(&mut self, diagnostic: Diagnostic) {
+        self.diagnostics.push(diagnostic);
+    }
+
+    fn error<M: ToString>(&self, span: Span, message: M) -> Diagnostic {
+        Diagnostic::error(self.src, span, message)
+    }
+
+    fn warning<M: ToString>(&self, span: Span, message: M) -> Diagnostic {
+        Diagnostic::warning(self.src, span, message)
+    }
+
+    fn line(&self, span: &Span) -> usize {
+        SourceSpan::new(self.src, span.clone()).line
+    }
+
+    fn lower_branch(&mut self, state_data: &mut StateData, branch: BranchDecl) {
+        let span = branch.span.clone();
+        let is_always = matches!(branch.kind, BranchKind::Always);
+        let is_otherwise = matches!(branch.kind, BranchKind::Otherwise);
+
+        if state_data.has_always || state_data.has_otherwise {
+            self.report(self.error(span.clone(), "Branch defined after always or otherwise.")
+                .with_hint("`always` and `otherwise` must be the last branch of a state"));
+        }
+
+        let has_other_branches = state_data.inherited_branches + state_data.branches.len() > 0;
+        let cond = match branch.kind {
+            BranchKind::When(cond) => cond,
+            BranchKind::Always if has_other_branches => {
+                self.report(self.error(span.clone(), "Always defined after another branch. Always must be the only branch.")
+                    .with_hint("use `otherwise` for a branch that runs when no other does"));
+                Expression::Value(Value::Bool(true))
+            },
+            BranchKind::Normally if has_other_branches => {
+                self.report(self.error(span.clone(), "Normally defined after another branch. Normally must be the first branch."));
+                Expression::Value(Value::Bool(true))
+            },
+            BranchKind::Otherwise if !has_other_branches && !state_data.has_errors => {
+                self.report(self.error(span.clone(), "Otherwise defined alone. Otherwise must come after at least one other branch.")
+                    .with_hint("use `always` for a state with a single unconditional branch"));
+                Expression::Value(Value::Bool(true))
+            },
+            _ => Expression::Value(Value::Bool(true)),
+        };
+        state_data.has_always |= is_always;
+        state_data.has_otherwise |= is_otherwise;
+
+        let label = self.src[span.clone()].to_string();
+        let mut branch_data = StateBranchData::new(cond, label, self.line(&span), span);
+        let mut state_op_span: Option<Span> = None;
+        for line in branch.lines {
+            let state_op = match line.kind {
+                LineKind::Expr(_) | LineKind::If(_) | LineKind::For(_) => {
+                    let statement = self.lower_statement(line);
+                    branch_data.branch.body.push(statement);
+                    continue;
+                },
+                LineKind::Default => {
+                    if state_data.has_default {
+                        let name = &state_data.name;
+                        let message = format!("Multiple branches marked as default in state {name}.");
+                        self.report(self.error(line.span, message)
+                            .with_hint("only one branch per state can be run by `advance`"));
+                    }
+                    branch_data.is_default = true;
+                    state_data.has_default = true;
+                    continue;
+                },
+                LineKind::Error => continue,
+                LineKind::ChangeTo(target, span, actions) => {
+                    self.transitions.push((state_data.name.clone(), target.clone(), span));
+                    let actions = self.lower_block(actions, "transition actions");
+                    StateOp::ChangeTo(Transition { target, actions })
+                },
+                LineKind::End => StateOp::End,
+                LineKind::Stay => StateOp::Stay,
+            };
+
+            if let Some(previous) = state_op_span.replace(line.span.clone()) {
+                let previous = self.line(&previous);
+                self.report(self.warning(line.span, format!("State operation overrides the one on line {previous}."))
+                    .with_hint("only the last state operation in a branch takes effect"));
+            }
+            branch_data.branch.state_op = state_op;
+        }
+
+        state_data.branches.push(branch_data);
+    }
+
+    /// Lower an expression, `if` or `for` line.
+    fn lower_statement(&mut self, line: Line) -> Statement {
+        let kind = match line.kind {
+            LineKind::Expr(expression) => StatementKind::Expr(expression),
+            LineKind::If(arms) => {
+                let mut conditions = Vec::new();
+                let mut otherwise = Vec::new();
+                for arm in arms {
+                    let body = self.lower_block(arm.lines, "`if` or `for` block");
+                    match arm.condition {
+                        Some(condition) => conditions.push((condition, body)),
+                        None => { otherwise = body; },
+                    }
+                }
+                StatementKind::If(conditions, otherwise)
+            },
+            LineKind::For(for_loop) => {
+                if !matches!(for_loop.target, Expression::Identifier(_)) {
+                    self.report(self.error(for_loop.span.clone(), "For loop variable must be an identifier.")
+                        .with_hint("loop over a list with e.g. `for locals.item in inputs.items:`"));
+                }
+                let body = self.lower_block(for_loop.lines, "`if` or `for` block");
+                StatementKind::For(for_loop.target, for_loop.list, body)
+            },
+            _ => unreachable!("only expression, `if` and `for` lines are statements"),
+        };
+        Statement { kind, line: self.line(&line.span) }
+    }
+
+    /// Heads, `on enter`/`on exit` blocks and blocks within a branch (of `if`, `for` and
+    /// transition actions) hold statements only; the branch itself decides the next state.
+    fn lower_block(&mut self, lines: Vec<Line>, block: &str) -> Vec<Statement> {
+        let mut statements = Vec::new();
+        for line in lines {
+            match line.kind {
+                LineKind::Expr(_) | LineKind::If(_) | LineKind::For(_) => {
+                    let statement = self.lower_statement(line);
+                    statements.push(statement);
+                },
+                LineKind::Error => (),
+                _ => {
+                    self.report(self.error(line.span, format!("Unexpected state operation in {block}. State only changes at the top level of a branch."))
+                        .with_hint("choose the next state in a branch of its own (`when ...:`, `always:`, ...)"));
+                },
+            }
+        }
+        statements
+    }
+
+    /// Lower a state and the states nested in it, adding them to `states`. Returns its index.
+    ///
+    /// The state's full path is its name after `prefix`: the path of its parent, or the name of
+    /// its region.
+    fn lower_state(&mut self, state: StateDecl, prefix: &str, parent: Option<usize>, region: usize, states: &mut Vec<StateData>, declared: &mut HashMap<String, Span>) -> usize {
+        let name = match prefix {
+            "" => state.name,
+            prefix => format!("{prefix}.{}", state.name),
+        };
+        if let Some(first) = declared.get(&name) {
+            let first = self.line(first);
+            self.report(self.error(state.span.clone(), format!("Duplicate state {name}."))
+                .with_hint(format!("a state with this name is already defined on line {first}")));
+        }
+        else {
+            declared.insert(name.clone(), state.span.clone());
+        }
+
+        let mut state_data = StateData::new(name, state.span.clone(), parent, region);
+        // A parent with `always` or `otherwise` is reported below, rather than in each nested state.
+        if let Some(parent) = parent.map(|p| &states[p]).filter(|p| !p.has_always && !p.has_otherwise) {
+            state_data.inherited_branches = parent.inherited_branches + parent.branches.len();
+        }
+        let mut children = Vec::new();
+        for state_item in state.items {
+            match state_item {
+                StateItem::Head(lines, _) => {
+                    let statements = self.lower_block(lines, "head");
+                    state_data.head.extend(statements);
+                },
+                StateItem::OnEnter(lines, _) => {
+                    let statements = self.lower_block(lines, "`on enter`");
+                    state_data.on_enter.extend(statements);
+                },
+                StateItem::OnExit(lines, _) => {
+                    let statements = self.lower_block(lines, "`on exit`");
+                    state_data.on_exit.extend(statements);
+                },
+                StateItem::Branch(branch) => self.lower_branch(&mut state_data, branch),
+                StateItem::State(child) => children.push(child),
+                StateItem::Error => state_data.has_errors = true,
+            }
+        }
+
+        if !children.is_empty() && (state_data.has_always || state_data.has_otherwise) {
+            self.report(self.error(state.span, format!("State {} has nested states, so can't have an `always` or `otherwise` branch.", state_data.name))
+                .with_hint("the branches of nested states would never run"));
+        }
+
+        let index = states.len();
+        states.push(state_data);
+        for child in children {
+            let prefix = states[index].name.clone();
+            let child = self.lower_state(child, &prefix, Some(index), region, states, declared);
+            states[index].children.push(child);
+        }
+        index
+    }
+
+    /// Resolve every `changeto` target to the full path of a state the machine can be in.
+    ///
+    /// A target is looked for among the states nested in the one the `changeto` is in, then among
+    /// its siblings, then those of its parent, and so on out to the top level. A composite state
+    /// is entered at its first nested state. Targets must be in the same `parallel` region.
+    fn check_transitions(&mut self, states: &[StateData]) -> HashMap<(String, String), String> {
+        let by_name: HashMap<&str, usize> = states.iter().enumerate()
+            .rev()
+            .map(|(i, state)| (state.name.as_str(), i))
+            .collect();
+
+        let mut resolved = HashMap::new();
+        for (scope, target, span) in std::mem::take(&mut self.transitions) {
+            let mut scopes: Vec<&str> = scope.match_indices('.').map(|(i, _)| &scope[..i]).collect();
+            scopes.push(&scope);
+            let found = scopes.iter().rev()
+                .map(|scope| format!("{scope}.{target}"))
+                .chain(std::iter::once(target.clone()))
+                .find_map(|name| by_name.get(name.as_str()).copied());
+
+            if let Some(index) = found {
+                if states[index].region != states[by_name[scope.as_str()]].region {
+                    self.report(self.error(span, format!("Can't change to {target}, which is in another region."))
+                        .with_hint("`parallel` regions each change state on their own"));
+                    continue;
+                }
+                let leaf = first_leaf(states, index);
+                resolved.insert((scope, target), states[leaf].name.clone());
+                continue;
+            }
+
+            // Suggest names as they could be written from here: full paths, or paths within any
+            // of the enclosing scopes.
+            let mut diagnostic = self.error(span, format!("No state named {target}."));
+            let closest = by_name.keys()
+                .flat_map(|name| {
+                    let within = scopes.iter().filter_map(|scope| name.strip_prefix(scope)?.strip_prefix('.'));
+                    std::iter::once(*name).chain(within)
+                })
+                .map(|name| (edit_distance(name, &target), name))
+                .filter(|(d, _)| *d < target.len() && *d <= 1.max(target.len() / 3))
+                .min();
+            if let Some((_, name)) = closest {
+                diagnostic = diagnostic.with_hint(format!("did you mean {name}?"));
+            }
+            self.report(diagnostic);
+        }
+        resolved
+    }
+
+    fn lower(&mut self, program: Program) -> Option<StateMachine> {
+        let mut default_head = Vec::new();
+        let mut states: Vec<StateData> = Vec::new();
+        let mut declared: HashMap<String, Span> = HashMap::new();
+
+        // Names of `parallel` regions; region 0, outside `parallel`, has no name.
+        let mut regions = vec![String::new()];
+
+        for item in program.items {
+            match item {
+                Item::DefaultHead(lines, _) => {
+                    let statements = self.lower_block(lines, "head");
+                    default_head.extend(statements);
+                },
+                Item::State(state) => { self.lower_state(state, "", None, 0, &mut states, &mut declared); },
+                Item::Parallel(region_decls, _) => {
+                    for region in region_decls {
+                        if regions.contains(&region.name) {
+                            self.report(self.error(region.span.clone(), format!("Duplicate region {}.", region.name)));
+                        }
+                        if region.states.is_empty() {
+                            self.report(self.error(region.span.clone(), format!("Region {} has no states.", region.name)));
+                        }
+                        regions.push(region.name.clone());
+                        for state in region.states.into_iter().flatten() {
+                            self.lower_state(state, &region.name, None, regions.len() - 1, &mut states, &mut declared);
+                        }
+                    }
+                },
+                Item::Error => (),
+            }
+        }
+
+        if regions.len() > 1 {
+            if let Some(outside) = states.iter().find(|state| state.region == 0) {
+                self.report(self.error(outside.span.clone(), format!("State {} is outside the `parallel` block.", outside.name))
+                    .with_hint("when a machine has `parallel` regions, every state must be in one of them"));
+            }
+        }
+
+        let resolved = self.check_transitions(&states);
+        if states.is_empty() {
+            let end = self.src.len();
+            self.report(self.error(end..end, "No states defined.")
+                .with_hint("a machine needs at least one `state <name>:` block, the first is the initial state"));
+        }
+
+        if self.diagnostics.iter().any(Diagnostic::is_error) {
+            return None;
+        }
+
+        for state_data in states.iter_mut() {
+            for branch in state_data.branches.iter_mut() {
+                if let StateOp::ChangeTo(transition) = &mut branch.branch.state_op {
+                    transition.target = resolved[&(state_data.name.clone(), transition.target.clone())].clone();
+                }
+            }
+        }
+
+        // Parents come before their children, so each has inherited from its own parent already.
+        for i in 0..states.len() {
+            if let Some(parent) = states[i].parent {
+                let (before, after) = states.split_at_mut(i);
+                after[0].inherit(&before[parent]);
+            }
+        }
+
+        // Each region starts in its first state.
+        let start = usize::from(regions.len() > 1);
+        let initial_states: Vec<(String, String)> = regions.into_iter()
+            .enumerate()
+            .skip(start)
+            .filter_map(|(region, name)| {
+                let first = states.iter().position(|state| state.region == region)?;
+                Some((name, states[first_leaf(&states, first)].name.clone()))
+            })
+            .collect();
+
+        let mut leaves = HashMap::new();
+        let mut composites = HashMap::new();
+        for state_data in states {
+            let span = state_data.span.clone();
+            let is_composite = !state_data.children.is_empty();
+            match State::try_from(state_data) {
+                Ok(state) if is_composite => { composites.insert(state.name().clone(), Box::new(state)); },
+                Ok(state) => { leaves.insert(state.name().clone(), Box::new(state)); },
+                Err(e) => self.report(self.error(span, e)),
+            }
+        }
+        if self.diagnostics.iter().any(Diagnostic::is_error) {
+            return None;
+        }
+        let regions = initial_states.into_iter()
+            .map(|(name, initial_state)| (name, leaves[&initial_state].clone()))
+            .collect();
+
+        Some(StateMachine::new(
+            default_head,
+            leaves,
+            composites,
+            regions,
+        ))
+    }
+}
+
+
+/// Compile SML source, reporting every problem found rather than just the first. Parsing picks
+/// up again at the next statement, branch, or state after a syntax error. If the source
+/// compiles, warnings from [crate::analysis::analyse] are included too.
+///
+/// Returns the machine if there were no errors (warnings are allowed), along with all
+/// diagnostics in source order.
+/// ```
+/// use shakemyleg::compile_with_diagnostics;
+///
+/// let src = r#"
+/// state A:
+///   when inputs.x >:
+///     changeto B
+///   otherwise:
+///     outputs.y = = 1
+/// state B:
+///   always:
+///     end
+/// "#;
+///
+/// let (sm, diagnostics) = compile_with_diagnostics(src);
+/// assert!(sm.is_none());
+/// assert_eq!(diagnostics.len(), 2);
+/// ```
+pub fn compile_with_diagnostics(s: &str) -> (Option<StateMachine>, Vec<Diagnostic>) {
+    compile_with_diagnostics_and_registry(s, &Registry::new())
+}
+
+
+/// As [compile_with_diagnostics], with native functions from `registry` available to call.
+pub fn compile_with_diagnostics_and_registry(s: &str, registry: &Registry) -> (Option<StateMachine>, Vec<Diagnostic>) {
+    let to_diagnostics = |errors: Vec<ParseError>| -> Vec<Diagnostic> {
+        errors.into_iter().map(|e| e.into_diagnostic(s)).collect()
+    };
+
+    let tokens = match lex(s) {
+        Ok(tokens) => tokens,
+        Err(errors) => return (None, to_diagnostics(errors)),
+    };
+    let (program, errors) = parse(tokens, s.len(), Some(registry));
+
+    let mut lowering = Lowering::new(s);
+    lowering.diagnostics = to_diagnostics(errors);
+    let sm = program.and_then(|program| lowering.lower(program));
+
+    let mut diagnostics = lowering.diagnostics;
+    if let Some(sm) = &sm {
+        diagnostics.extend(analyse(sm, s));
+    }
+    diagnostics.sort_by_key(|d| d.span.range.start);
+    let sm = if diagnostics.iter().any(Diagnostic::is_error) { None } else { sm };
+    (sm, diagnostics)
+}
+
+
 /// Take a string of SML source and compile to state machine.
 /// ```
 /// use shakemyleg::compile;
@@ -110,248 +596,43 @@ impl StateBranchData {
 ///
 /// let sm = compile(src).unwrap();
 /// ```
+///
+/// Warnings are ignored; if there are any errors, the first is returned.
 pub fn compile(s: &str) -> SML_Result<StateMachine> {
-    let mut c_state_stack = vec![CompileState::TopLevel];
-    let lines: Vec<_> = s.lines().collect();
-    let mut i = 0usize;
-    let mut state_data: Option<StateData> = None;
-    let mut state_branch_data: Option<StateBranchData> = None;
-    let mut default_head = Vec::new();
-    let mut states: Vec<State> = Vec::new();
-    let mut leading_ws = None;
-
-    let nlines = lines.len();
-    while i < nlines {
-        let line = lines[i];
-        let cstate = c_state_stack.last().unwrap();
-
-        if line.trim_start().starts_with("#") {
-            i += 1;
-            continue;
-        }
-
-        let line_empty = line.trim() == "";
-
-        if leading_ws.is_none() && matches!(cstate, CompileState::DefaultHead | CompileState::State) {
-            let line_no_ws = line.trim_start();
-            let ws = line.strip_suffix(line_no_ws).unwrap();
-            let ws2 = ws.to_string() + ws;
-            leading_ws = Some((ws, ws2));
-        }
-
-        let adv = match cstate {
-            CompileState::TopLevel => {
-                // 
-                if let Some(sname_colon) = line.strip_prefix("state ") {
-                    if let Some(sname) = sname_colon.strip_suffix(":") {
-                        state_data = Some(StateData::new(sname.to_string()));
-                        c_state_stack.push(CompileState::State);
-                        true
-                    }
-                    else {
-                        return Err(SML_Error::SyntaxError(format!("State definition with no name on line {i}")));
-                    }
-                }
-                else if line == "default head:" {
-                    c_state_stack.push(CompileState::DefaultHead);
-                    true
-                }
-                else if !line.is_empty() {
-                    return Err(SML_Error::SyntaxError(format!("Unexpected value {line} on line {i}")));
-                }
-                else {
-                    true
-                }
-            },
-            CompileState::State => {
-                if line.starts_with(&leading_ws.as_ref().unwrap().1) {
-                    return Err(SML_Error::SyntaxError(format!("Unexpected indent on line {i}")));
-                }
-                else if line.starts_with(&leading_ws.as_ref().unwrap().0) {
-                    if !line_empty {
-                        let line_trim = line.trim_start();
-                        if line_trim == "head:" {
-                            c_state_stack.push(CompileState::StateHead);
-                        }
-                        else if let Some(expr_colon) = line_trim.strip_prefix("when ") {
-                            let has_always = state_data.as_ref().unwrap().has_always;
-                            let has_otherwise = state_data.as_ref().unwrap().has_otherwise;
-                            if has_always || has_otherwise {
-                                return Err(SML_Error::SyntaxError(format!("Branch defined after always or otherwise on line {i}")));
-                            }
-
-                            if let Some(expr) = expr_colon.strip_suffix(":") {
-                                let cond = expr_from_str(expr, i)?;
-                                state_branch_data = Some(StateBranchData::new(cond));
-                                c_state_stack.push(CompileState::StateBranch);
-                            }
-                            else {
-                                return Err(SML_Error::SyntaxError(format!("Missing colon on line {i}:{line}")));
-                            }
-                        }
-                        else if line_trim == "always:" {
-                            let has_always = state_data.as_ref().unwrap().has_always;
-                            let has_otherwise = state_data.as_ref().unwrap().has_otherwise;
-                            if has_always || has_otherwise {
-                                return Err(SML_Error::SyntaxError(format!("Branch defined after always or otherwise on line {i}.")));
-                            }
-
-                            let has_other_branches = state_data.as_ref().unwrap().branches.len() > 0;
-                            if has_other_branches {
-                                return Err(SML_Error::SyntaxError(format!("Always defined after another branch on line {i}. Always must be the only branch.")));
-                            }
-
-                            let cond = Expression::Value(Value::Bool(true));
-                            state_branch_data = Some(StateBranchData::new(cond));
-                            state_data.as_mut().unwrap().has_always = true;
-                            c_state_stack.push(CompileState::StateBranch);
-                        }
-                        else if line_trim == "normally:" {
-                            let has_always = state_data.as_ref().unwrap().has_always;
-                            let has_otherwise = state_data.as_ref().unwrap().has_otherwise;
-                            if has_always || has_otherwise {
-                                return Err(SML_Error::SyntaxError(format!("Branch defined after always or otherwise on line {i}.")));
-                            }
-
-                            let has_other_branches = state_data.as_ref().unwrap().branches.len() > 0;
-                            if has_other_branches {
-                                return Err(SML_Error::SyntaxError(format!("Normally defined after another branch on line {i}. Normally must be the first branch.")));
-                            }
-
-                            let cond = Expression::Value(Value::Bool(true));
-                            state_branch_data = Some(StateBranchData::new(cond));
-                            c_state_stack.push(CompileState::StateBranch);
-                        }
-                        else if line_trim == "otherwise:" {
-                            let has_always = state_data.as_ref().unwrap().has_always;
-                            let has_otherwise = state_data.as_ref().unwrap().has_otherwise;
-                            if has_always || has_otherwise {
-                                return Err(SML_Error::SyntaxError(format!("Branch defined after always or otherwise on line {i}.")));
-                            }
-
-                            let has_other_branches = state_data.as_ref().unwrap().branches.len() > 0;
-                            if !has_other_branches {
-                                return Err(SML_Error::SyntaxError(format!("Otherwise defined alone on line {i}. Otherwise must come after at least one other branch.")));
-                            }
-
-                            let cond = Expression::Value(Value::Bool(true));
-                            state_branch_data = Some(StateBranchData::new(cond));
-                            state_data.as_mut().unwrap().has_otherwise = true;
-                            c_state_stack.push(CompileState::StateBranch);
-                        }
-                        else {
-                            eprintln!("{}", lines[i-1]);
-                            return Err(SML_Error::SyntaxError(format!("Expected ['head:', 'when <state>:', 'always:', 'otherwise:'] after state intro on line {i}:{line}")));
-                        }
-                    }
-                    true
-                }
-                else {
-                    if let Some(state_data) = state_data.take() {
-                        states.push(state_data.try_into()?);
-                        c_state_stack.pop();
-                        false
-                    }
-                    else {
-                        return Err(SML_Error::SyntaxError(format!("Unexpected de-dent on line {i}")));
-                    }
-                }
-            },
-            CompileState::DefaultHead => {
-                if line.starts_with(leading_ws.as_ref().unwrap().0) {
-                    let line = line.trim_start();
-                    let expr = expr_from_str(line, i)?;
-                    default_head.push(expr);
-                    true
-                }
-                else {
-                    // TODO warn if empty head?
-                    c_state_stack.pop();
-                    false
-                }
-            },
-            CompileState::StateHead => {
-                if line.starts_with(&leading_ws.as_ref().unwrap().1) {
-                    let line = line.trim_start();
-                    let expr = expr_from_str(line, i)?;
-                    state_data.as_mut().unwrap().head.push(expr);
-                    true
-                }
-                else {
-                    c_state_stack.pop();
-                    false
-                }
-            },
-            CompileState::StateBranch => {
-                if line.starts_with(&leading_ws.as_ref().unwrap().1) {
-                    let line = line.trim_start();
-                    if let Some(state_name) = line.strip_prefix("changeto ") {
-                        state_branch_data.as_mut().unwrap().state_op = StateOp::ChangeTo(state_name.to_string());
-                    }
-                    else if line == "end" {
-                        state_branch_data.as_mut().unwrap().state_op = StateOp::End;
-                    }
-                    else if line == "stay" {
-                        state_branch_data.as_mut().unwrap().state_op = StateOp::Stay;
-                    }
-                    else if line == "default" {
-                        if state_data.as_ref().unwrap().has_default {
-                            let name = &state_data.as_ref().unwrap().name;
-                            return Err(SML_Error::SyntaxError(format!("Multiple branches marked as default in state {name}. On line {i}.")));
-                        }
-                        else {
-                            state_branch_data.as_mut().unwrap().is_default = true;
-                            state_data.as_mut().unwrap().has_default = true;
-                        }
-                    }
-                    else {
-                        let expr = expr_from_str(line, i)?;
-                        state_branch_data.as_mut().unwrap().body.push(expr);
-                    }
-                    true
-                }
-                else {
-                    let branch = state_branch_data.take().unwrap();
-                    state_data.as_mut().unwrap().branches.push(branch);
-                    c_state_stack.pop();
-                    false
-                }
-            }
-        };
-
-        if adv {
-            i += 1;
-        }
-    }
+    compile_with_registry(s, &Registry::new())
+}
 
-    if let Some(branch) = state_branch_data {
-        state_data.as_mut().unwrap().branches.push(branch);
-    }
 
-    if let Some(state_data) = state_data {
-        states.push(state_data.try_into()?);
+/// As [compile], with native functions from `registry` available to call. See [Registry].
+pub fn compile_with_registry(s: &str, registry: &Registry) -> SML_Result<StateMachine> {
+    match compile_with_diagnostics_and_registry(s, registry) {
+        (Some(sm), _) => Ok(sm),
+        (None, diagnostics) => {
+            let first = diagnostics.into_iter().find(Diagnostic::is_error)
+                .ok_or_else(|| SML_Error::CompilerError("compilation failed without an error".to_string()))?;
+            Err(first.into())
+        },
     }
+}
 
-    let initial_state = states[0].name().clone();
-    let states_iter = states.into_iter();
-    let mut states = HashMap::new();
-    for state in states_iter {
-        states.insert(state.name().clone(), Box::new(state));
-    }
-    let initial_state = states.get(&initial_state).unwrap().clone();
 
-    Ok(StateMachine::new(
-        default_head,
-        states,
-        initial_state,
-    ))
+/// Read and compile an SML source file. Diagnostics in any error refer to the file by `path`.
+pub fn compile_file<P: AsRef<Path>>(path: P) -> SML_Result<StateMachine> {
+    let path = path.as_ref();
+    let src = std::fs::read_to_string(path)?;
+    compile(&src).map_err(|e| match e {
+        SML_Error::SyntaxError(d) => d.with_file(path.display()).into(),
+        e => e,
+    })
 }
 
 
 #[cfg(test)]
+#[allow(clippy::redundant_static_lifetimes)]
 mod tests {
 
     use super::*;
+    use crate::diagnostic::Severity;
     use serde::{Serialize, Deserialize};
 
     #[derive(Serialize, Deserialize)]
@@ -492,6 +773,221 @@ state B:
         let _ = compile(SRC).unwrap();
     }
 
+    #[derive(Serialize)]
+    struct InXY {
+        x: f64,
+        y: f64,
+    }
+
+    #[derive(Deserialize, PartialEq, Debug)]
+    struct OutX {
+        x: f64,
+    }
+
+    #[test]
+    fn test_compile_whitespace_insensitive() {
+        const SPACED: &str = r#"
+state A:
+    when inputs.x > 1:
+        outputs.x = inputs.y * 2
+        changeto B
+    otherwise:
+        outputs.x = 0
+state B:
+    always:
+        outputs.x = inputs.y
+"#;
+        const CRAMPED: &str = "state A:  # trailing comment\n\twhen inputs.x>1 :\n\t\toutputs.x=inputs.y*2\n\t\tchangeto   B # another\n\totherwise:\n\t\toutputs.x=0\n\nstate B:\n  always:\n      outputs.x = (inputs.y\n          )\n";
+
+        for src in [SPACED, CRAMPED] {
+            let mut sm = compile(src).unwrap();
+            let o: OutX = sm.run(InXY { x: 2.0, y: 3.0 }).unwrap().unwrap();
+            assert_eq!(o, OutX { x: 6.0 });
+            assert_eq!(sm.current_state().unwrap(), "B".to_string());
+            let o: OutX = sm.run(InXY { x: 2.0, y: 3.0 }).unwrap().unwrap();
+            assert_eq!(o, OutX { x: 3.0 });
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_compile_bad_dedent() {
+        const SRC: &str = r#"
+state A:
+    always:
+        outputs.x = 1
+      changeto A
+"#;
+        let _ = compile(SRC).unwrap();
+    }
+
+    #[test]
+    fn test_compile_error_span() {
+        const SRC: &str = r#"
+state A:
+    always:
+        outputs.x = 1
+    otherwise:
+        end
+"#;
+        match compile(SRC) {
+            Err(SML_Error::SyntaxError(d)) => {
+                assert_eq!(d.span.line, 5);
+                assert_eq!(d.span.column, 5);
+                assert_eq!(&SRC[d.span.range.clone()], "otherwise");
+                assert!(d.hint.is_some());
+            },
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_compile_file_names_span() {
+        let path = std::env::temp_dir().join("sml_test_compile_file_names_span.sml");
+        std::fs::write(&path, "state A:\n    when true\n        end\n").unwrap();
+        let rv = compile_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        match rv {
+            Err(SML_Error::SyntaxError(d)) => {
+                assert_eq!(d.span.file, Some(path.display().to_string()));
+                assert_eq!((d.span.line, d.span.column), (2, 14));
+            },
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_runtime_error_location() {
+        const SRC: &str = r#"
+state A:
+    head:
+        outputs.bar = 1
+    when inputs.bar > 0:
+        outputs.bar = inputs.missing
+"#;
+        let mut sm = compile(SRC).unwrap();
+        let rv: SML_Result<Option<Foo>> = sm.run(Foo { bar: 1 });
+        match rv {
+            Err(e @ SML_Error::RuntimeError { .. }) => {
+                assert!(matches!(e.inner(), SML_Error::IdentifierNameError(_)));
+                match e {
+                    SML_Error::RuntimeError { location, .. } => {
+                        assert_eq!(location.line, 6);
+                        assert_eq!(location.state, "A");
+                        assert_eq!(location.block, "when inputs.bar > 0");
+                    },
+                    _ => unreachable!(),
+                }
+            },
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_compile_reports_all_errors() {
+        const SRC: &str = r#"
+state A:
+    when inputs.bar >:
+        changeto B
+    when inputs.bar < 0:
+        outputs.bar = = 1
+        changeto B
+    otherwise:
+        stay
+state :
+    always:
+        end
+state B:
+    foo:
+        bar
+    always:
+        outputs.bar = 1
+"#;
+        let (sm, diagnostics) = compile_with_diagnostics(SRC);
+        assert!(sm.is_none());
+        let lines: Vec<_> = diagnostics.iter().map(|d| d.span.line).collect();
+        assert_eq!(lines, vec![3, 6, 10, 14]);
+        assert!(diagnostics.iter().all(Diagnostic::is_error));
+    }
+
+    #[test]
+    fn test_compile_warnings_allowed() {
+        const SRC: &str = r#"
+state A:
+    always:
+        changeto A
+        end
+"#;
+        let (sm, diagnostics) = compile_with_diagnostics(SRC);
+        assert!(sm.is_some());
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert_eq!(diagnostics[0].span.line, 5);
+        assert!(compile(SRC).is_ok());
+    }
+
+    #[test]
+    fn test_compile_unknown_state() {
+        const SRC: &str = r#"
+state Heating:
+    when inputs.bar > 10:
+        changeto Coolign
+state Cooling:
+    always:
+        changeto Heating
+"#;
+        match compile(SRC) {
+            Err(SML_Error::SyntaxError(d)) => {
+                assert_eq!(&SRC[d.span.range.clone()], "Coolign");
+                assert_eq!(d.hint.as_deref(), Some("did you mean Cooling?"));
+            },
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_compile_unknown_state_no_hint() {
+        const SRC: &str = r#"
+state A:
+    always:
+        changeto Cc
+"#;
+        match compile(SRC) {
+            Err(SML_Error::SyntaxError(d)) => {
+                assert_eq!(d.message, "No state named Cc.");
+                assert_eq!(d.hint, None);
+            },
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_compile_duplicate_state() {
+        const SRC: &str = r#"
+state A:
+    always:
+        outputs.bar = 1
+state B:
+    always:
+        changeto A
+state A:
+    always:
+        outputs.bar = 2
+"#;
+        let (sm, diagnostics) = compile_with_diagnostics(SRC);
+        assert!(sm.is_none());
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].span.line, 8);
+        assert_eq!(diagnostics[0].hint.as_deref(), Some("a state with this name is already defined on line 2"));
+    }
+
+    #[test]
+    fn test_compile_no_states() {
+        for src in ["", "# nothing here\n", "default head:\n    outputs.bar = 1\n"] {
+            assert!(matches!(compile(src), Err(SML_Error::SyntaxError(_))));
+        }
+    }
+
     #[derive(Serialize)]
     struct InFoo {
         foo: Vec<u8>
@@ -558,4 +1054,502 @@ state final:
         assert_eq!(o.bar, 1u8);
     }
 
+    #[derive(Serialize)]
+    struct Sensor {
+        name: String,
+        readings: Vec<f64>,
+    }
+
+    #[derive(Serialize)]
+    struct InSensor {
+        sensor: Sensor,
+    }
+
+    #[test]
+    fn test_compile_nested_paths() {
+        const SRC: &str = r#"
+state A:
+    head:
+        outputs.reading.last = inputs.sensor.readings[-1]
+        globals.counts[inputs.sensor.name] = inputs.sensor.readings[0]
+    always:
+        outputs.count = globals.counts["x"]
+"#;
+        let mut sm = compile(SRC).unwrap();
+
+        let i = InSensor { sensor: Sensor { name: "x".to_string(), readings: vec![1.0, 2.0, 3.0] } };
+        let o: serde_json::Value = sm.run(i).unwrap().unwrap();
+        assert_eq!(o, serde_json::json!({ "reading": { "last": 3 }, "count": 1 }));
+    }
+
+    #[derive(Serialize)]
+    struct InMessage {
+        id: Option<u8>,
+        sensor: Sensor,
+    }
+
+    #[test]
+    fn test_compile_objects_and_null() {
+        const SRC: &str = r#"
+state A:
+    when inputs.id == null:
+        outputs.sensor = inputs.sensor
+        outputs.meta = { "source": "A", "tags": [1, 2], "parent": null, }
+    otherwise:
+        outputs.id = inputs.id
+"#;
+        let mut sm = compile(SRC).unwrap();
+
+        let i = InMessage { id: None, sensor: Sensor { name: "x".to_string(), readings: vec![1.0] } };
+        let o: serde_json::Value = sm.run(i).unwrap().unwrap();
+        assert_eq!(o, serde_json::json!({
+            "sensor": { "name": "x", "readings": [1] },
+            "meta": { "source": "A", "tags": [1, 2], "parent": null },
+        }));
+
+        let i = InMessage { id: Some(3), sensor: Sensor { name: "x".to_string(), readings: vec![] } };
+        let o: serde_json::Value = sm.run(i).unwrap().unwrap();
+        assert_eq!(o, serde_json::json!({ "id": 3 }));
+    }
+
+    #[test]
+    fn test_compile_function_calls() {
+        const SRC: &str = r#"
+state A:
+    when abs(inputs.x) > 1:
+        outputs.x = clamp(inputs.x, -1, 1)
+    otherwise:
+        outputs.x = round(max(inputs.x, 0.4) * 2)
+"#;
+        let mut sm = compile(SRC).unwrap();
+
+        let o: OutX = sm.run(InXY { x: -3.0, y: 0.0 }).unwrap().unwrap();
+        assert_eq!(o.x, -1.0);
+        let o: OutX = sm.run(InXY { x: 0.0, y: 0.0 }).unwrap().unwrap();
+        assert_eq!(o.x, 1.0);
+    }
+
+    #[test]
+    fn test_compile_bad_function_calls() {
+        const SRC: &str = r#"
+state A:
+    always:
+        outputs.x = abs(inputs.x, 1)
+        outputs.y = frobnicate(inputs.x)
+"#;
+        let (sm, diagnostics) = compile_with_diagnostics(SRC);
+        assert!(sm.is_none());
+        let messages: Vec<_> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages, ["abs takes 1 argument, got 2.", "Unknown function \"frobnicate\"."]);
+        assert_eq!(&SRC[diagnostics[0].span.range.clone()], "abs(inputs.x, 1)");
+    }
+
+    #[test]
+    fn test_compile_native_functions() {
+        const SRC: &str = r#"
+state A:
+    when is_hot(inputs.x):
+        outputs.x = to_fahrenheit(inputs.x)
+    otherwise:
+        outputs.x = 0
+"#;
+        assert!(compile(SRC).is_err());
+
+        let registry = Registry::new()
+            .register_with_arity("is_hot", 1, |args| Ok(Value::Bool(args[0] == Value::Number(100.0))))
+            .register("to_fahrenheit", |args| match args {
+                [Value::Number(c)] => Ok(Value::Number(c * 9.0 / 5.0 + 32.0)),
+                _ => Err(SML_Error::BadOperation("to_fahrenheit takes a number".to_string())),
+            });
+        let mut sm = compile_with_registry(SRC, &registry).unwrap();
+
+        let o: OutX = sm.run(InXY { x: 100.0, y: 0.0 }).unwrap().unwrap();
+        assert_eq!(o.x, 212.0);
+        let o: OutX = sm.run(InXY { x: 20.0, y: 0.0 }).unwrap().unwrap();
+        assert_eq!(o.x, 0.0);
+    }
+
+    #[test]
+    fn test_compile_strings() {
+        const SRC: &str = r#"
+state A:
+    head:
+        globals.n = 3
+    when inputs.sensor.name ^= "temp":
+        outputs.msg = f"{upper(inputs.sensor.name)}: {inputs.sensor.readings[0]} after {globals.n} runs"
+    otherwise:
+        outputs.msg = "no " + inputs.sensor.name
+"#;
+        let mut sm = compile(SRC).unwrap();
+
+        let i = InSensor { sensor: Sensor { name: "temp1".to_string(), readings: vec![21.5] } };
+        let o: serde_json::Value = sm.run(i).unwrap().unwrap();
+        assert_eq!(o["msg"], "TEMP1: 21.5 after 3 runs");
+
+        let i = InSensor { sensor: Sensor { name: "flow".to_string(), readings: vec![] } };
+        let o: serde_json::Value = sm.run(i).unwrap().unwrap();
+        assert_eq!(o["msg"], "no flow");
+    }
+
+    #[test]
+    fn test_compile_rolling_window() {
+        const SRC: &str = r#"
+state Start:
+    always:
+        globals.buf = [] + inputs.x
+        outputs.x = inputs.x
+        changeto Rolling
+state Rolling:
+    always:
+        globals.buf = slice(globals.buf + inputs.x, -3)
+        outputs.x = mean(globals.buf)
+"#;
+        let mut sm = compile(SRC).unwrap();
+
+        let means: Vec<f64> = [3.0, 6.0, 9.0, 0.0]
+            .into_iter()
+            .map(|x| sm.run::<_, OutX>(InXY { x, y: 0.0 }).unwrap().unwrap().x)
+            .collect();
+        assert_eq!(means, [3.0, 4.5, 6.0, 5.0]);
+    }
+
+    #[test]
+    fn test_compile_bit_flags() {
+        const SRC: &str = r#"
+state A:
+    when inputs.x & 4 == 4 ? inputs.y > 0 : false:
+        outputs.x = (inputs.x >> 1) % 2 + inputs.x // 8
+    otherwise:
+        outputs.x = inputs.x xor 1 ? 10 : -10
+"#;
+        let mut sm = compile(SRC).unwrap();
+
+        let o: OutX = sm.run(InXY { x: 14.0, y: 1.0 }).unwrap().unwrap();
+        assert_eq!(o.x, 2.0);
+        let o: OutX = sm.run(InXY { x: 14.0, y: 0.0 }).unwrap().unwrap();
+        assert_eq!(o.x, 10.0);
+        let o: OutX = sm.run(InXY { x: 1.0, y: 1.0 }).unwrap().unwrap();
+        assert_eq!(o.x, -10.0);
+    }
+
+    #[test]
+    fn test_compile_counters() {
+        const SRC: &str = r#"
+default head:
+    globals.runs++
+state A:
+    head:
+        globals.total += inputs.x
+    when not inputs.y > 0:
+        outputs.x = -globals.total
+    otherwise:
+        outputs.x = globals.runs
+"#;
+        let mut sm = compile(SRC).unwrap();
+        sm.reinit(serde_json::json!({ "runs": 0, "total": 0 })).unwrap();
+
+        let o: OutX = sm.run(InXY { x: 2.0, y: 0.0 }).unwrap().unwrap();
+        assert_eq!(o.x, -2.0);
+        let o: OutX = sm.run(InXY { x: 3.0, y: 1.0 }).unwrap().unwrap();
+        assert_eq!(o.x, 2.0);
+    }
+
+    #[test]
+    fn test_compile_bad_literals() {
+        const SRC: &str = r#"
+state A:
+    always:
+        outputs.x = 1.5e
+        outputs.s = "tab\q"
+"#;
+        let (sm, diagnostics) = compile_with_diagnostics(SRC);
+        assert!(sm.is_none());
+        let messages: Vec<_> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages, ["Malformed number '1.5e'", "Unknown escape sequence '\\q'"]);
+        assert_eq!(&SRC[diagnostics[0].span.range.clone()], "1.5e");
+    }
+
+    #[test]
+    fn test_compile_comparisons() {
+        const SRC: &str = r#"
+state A:
+    when inputs.sensor.readings == [0.5, 1] && inputs.sensor.name < "m":
+        outputs.msg = "exact"
+    when inputs.sensor.readings[0] ~= 0.1 + 0.2:
+        outputs.msg = "approx"
+    when inputs.sensor.name == 1:
+        outputs.msg = "never"
+"#;
+        let mut sm = compile(SRC).unwrap();
+
+        let i = InSensor { sensor: Sensor { name: "a".to_string(), readings: vec![0.5, 1.0] } };
+        let o: serde_json::Value = sm.run(i).unwrap().unwrap();
+        assert_eq!(o["msg"], "exact");
+
+        let i = InSensor { sensor: Sensor { name: "a".to_string(), readings: vec![0.3] } };
+        let o: serde_json::Value = sm.run(i).unwrap().unwrap();
+        assert_eq!(o["msg"], "approx");
+
+        // Comparing a string with a number is an error, not a quiet `false`.
+        let i = InSensor { sensor: Sensor { name: "a".to_string(), readings: vec![1.0] } };
+        let e = sm.run::<_, serde_json::Value>(i).unwrap_err();
+        assert!(e.to_string().contains("Cannot compare string with number using '=='"), "{e}");
+    }
+
+    #[test]
+    fn test_compile_locals() {
+        const SRC: &str = r#"
+state A:
+    head:
+        locals.big = inputs.x > 10
+        locals.scaled = inputs.x * 2
+    when locals.big:
+        outputs.x = locals.scaled
+        changeto B
+    otherwise:
+        outputs.x = 0
+state B:
+    when inputs.y > 0:
+        outputs.x = locals.scaled
+    otherwise:
+        outputs.x = 1
+"#;
+        let mut sm = compile(SRC).unwrap();
+
+        let o: serde_json::Value = sm.run(InXY { x: 20.0, y: 0.0 }).unwrap().unwrap();
+        assert_eq!(o, serde_json::json!({ "x": 40 }));
+        let g: serde_json::Value = sm.globals().unwrap();
+        assert_eq!(g, serde_json::json!({ }));
+
+        // Locals don't last from one run to the next.
+        let o: OutX = sm.run(InXY { x: 20.0, y: 0.0 }).unwrap().unwrap();
+        assert_eq!(o.x, 1.0);
+        assert!(sm.run::<_, OutX>(InXY { x: 20.0, y: 1.0 }).is_err());
+    }
+
+    #[test]
+    fn test_compile_if_and_for() {
+        const SRC: &str = r#"
+state A:
+    head:
+        locals.total = 0
+        for locals.r in inputs.readings:
+            if locals.r < 0:
+                outputs.negatives = true
+            elif locals.r > 100:
+                locals.total += 100
+            else:
+                locals.total += locals.r
+    always:
+        outputs.total = locals.total
+"#;
+        let mut sm = compile(SRC).unwrap();
+
+        let o: serde_json::Value = sm.run(serde_json::json!({ "readings": [1, 500, 2] })).unwrap().unwrap();
+        assert_eq!(o, serde_json::json!({ "total": 103 }));
+        let o: serde_json::Value = sm.run(serde_json::json!({ "readings": [-1] })).unwrap().unwrap();
+        assert_eq!(o, serde_json::json!({ "negatives": true, "total": 0 }));
+
+        let rv: SML_Result<Option<serde_json::Value>> = sm.run(serde_json::json!({ "readings": 3 }));
+        match rv {
+            Err(SML_Error::RuntimeError { location, .. }) => assert_eq!(location.line, 5),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_compile_bad_blocks() {
+        const SRC: &str = r#"
+state A:
+    when inputs.x > 0:
+        if inputs.x > 1:
+            changeto B
+        for 1 in inputs.xs:
+            outputs.x = 1
+    otherwise:
+        stay
+state B:
+    always:
+        end
+"#;
+        let (sm, diagnostics) = compile_with_diagnostics(SRC);
+        assert!(sm.is_none());
+        let messages: Vec<_> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages, ["Unexpected state operation in `if` or `for` block. State only changes at the top level of a branch.", "For loop variable must be an identifier."]);
+    }
+
+    #[test]
+    fn test_compile_actions() {
+        const SRC: &str = r#"
+state A:
+    on enter:
+        outputs.x = 1
+    on exit:
+        changeto A
+    always:
+        stay
+"#;
+        let (sm, diagnostics) = compile_with_diagnostics(SRC);
+        assert!(sm.is_none());
+        let messages: Vec<_> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages, ["Unexpected state operation in `on exit`. State only changes at the top level of a branch."]);
+    }
+
+    #[test]
+    fn test_compile_transition_actions() {
+        const SRC: &str = r#"
+state A:
+    always:
+        changeto B with:
+            outputs.x = 1
+            end
+state B:
+    always:
+        changeto C with outputs.x = 2
+"#;
+        let (sm, diagnostics) = compile_with_diagnostics(SRC);
+        assert!(sm.is_none());
+        let messages: Vec<_> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages, ["Unexpected state operation in transition actions. State only changes at the top level of a branch.", "No state named C."]);
+    }
+
+    #[test]
+    fn test_compile_nested_states() {
+        const SRC: &str = r#"
+state A:
+    head:
+        outputs.x = 1
+    state B:
+        always:
+            changeto C
+    state C:
+        head:
+            outputs.y = 2
+        when inputs.x > 0:
+            changeto D.B
+state D:
+    state B:
+        always:
+            end
+"#;
+        let mut sm = compile(SRC).unwrap();
+        assert_eq!(sm.current_state(), Some("A.B".to_string()));
+
+        let o: serde_json::Value = sm.run(serde_json::json!({ "x": 1 })).unwrap().unwrap();
+        assert_eq!(o, serde_json::json!({ "x": 1 }));
+        assert_eq!(sm.current_state(), Some("A.C".to_string()));
+
+        let o: serde_json::Value = sm.run(serde_json::json!({ "x": 1 })).unwrap().unwrap();
+        assert_eq!(o, serde_json::json!({ "x": 1, "y": 2 }));
+        assert_eq!(sm.current_state(), Some("D.B".to_string()));
+    }
+
+    #[test]
+    fn test_compile_nested_branch_order() {
+        // Inherited branches come first, so `otherwise` can follow them...
+        const SRC: &str = r#"
+state A:
+    when inputs.estop:
+        changeto Fault
+    state A1:
+        otherwise:
+            outputs.x = 1
+state Fault:
+    always:
+        end
+"#;
+        let mut sm = compile(SRC).unwrap();
+        let o: serde_json::Value = sm.run(serde_json::json!({ "estop": false })).unwrap().unwrap();
+        assert_eq!(o, serde_json::json!({ "x": 1 }));
+        let _: serde_json::Value = sm.run(serde_json::json!({ "estop": true })).unwrap().unwrap();
+        assert_eq!(sm.current_state(), Some("Fault".to_string()));
+
+        // ...but `always` can't.
+        const BAD: &str = r#"
+state A:
+    when inputs.estop:
+        end
+    state A1:
+        always:
+            stay
+"#;
+        let (sm, diagnostics) = compile_with_diagnostics(BAD);
+        assert!(sm.is_none());
+        let messages: Vec<_> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages, ["Always defined after another branch. Always must be the only branch."]);
+    }
+
+    #[test]
+    fn test_compile_bad_nested_states() {
+        const SRC: &str = r#"
+state A:
+    when inputs.x > 0:
+        changeto Q
+    otherwise:
+        stay
+    state B:
+        always:
+            changeto A.Bb
+    state B:
+        always:
+            stay
+"#;
+        let (sm, diagnostics) = compile_with_diagnostics(SRC);
+        assert!(sm.is_none());
+        let messages: Vec<_> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages, [
+            "State A has nested states, so can't have an `always` or `otherwise` branch.",
+            "No state named Q.",
+            "No state named A.Bb.",
+            "Duplicate state A.B.",
+        ]);
+        assert_eq!(diagnostics[2].hint.as_deref(), Some("did you mean A.B?"));
+    }
+
+    #[test]
+    fn test_compile_nested_hint() {
+        const SRC: &str = r#"
+state Running:
+    state Heating:
+        when inputs.hot:
+            changeto Colling
+    state Cooling:
+        when inputs.cold:
+            changeto Heating
+"#;
+        let (sm, diagnostics) = compile_with_diagnostics(SRC);
+        assert!(sm.is_none());
+        assert_eq!(diagnostics[0].message, "No state named Colling.");
+        assert_eq!(diagnostics[0].hint.as_deref(), Some("did you mean Cooling?"));
+    }
+
+    #[test]
+    fn test_compile_bad_parallel() {
+        const SRC: &str = r#"
+state Outside:
+    always:
+        stay
+parallel:
+    region A:
+        state A1:
+            always:
+                changeto B.B1
+    region A:
+        state A2:
+            always:
+                stay
+    region B:
+        state B1:
+            always:
+                stay
+"#;
+        let (sm, diagnostics) = compile_with_diagnostics(SRC);
+        assert!(sm.is_none());
+        let messages: Vec<_> = diagnostics.iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages, [
+            "State Outside is outside the `parallel` block.",
+            "Can't change to B.B1, which is in another region.",
+            "Duplicate region A.",
+        ]);
+    }
 }
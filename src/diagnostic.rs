@@ -0,0 +1,183 @@
+//! Located compiler messages, and rendering them against the offending source.
+use std::fmt;
+use std::ops::Range;
+
+
+/// Where in the source a [Diagnostic] points. Lines and columns count from one; columns count
+/// characters, the range counts bytes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceSpan {
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+    pub range: Range<usize>,
+
+    /// Full text of the (first) line the span is on, for rendering.
+    pub source_line: String,
+}
+
+impl SourceSpan {
+    pub fn new(src: &str, range: Range<usize>) -> Self {
+        let start = range.start.min(src.len());
+        let line_start = src[..start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let line_end = src[start..].find('\n').map(|i| start + i).unwrap_or(src.len());
+        let source_line = src[line_start..line_end].trim_end_matches('\r').to_string();
+        let line = src[..start].matches('\n').count() + 1;
+        let column = src[line_start..start].chars().count() + 1;
+        Self { file: None, line, column, range, source_line }
+    }
+}
+
+impl fmt::Display for SourceSpan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let file = self.file.as_deref().unwrap_or("<source>");
+        write!(f, "{file}:{}:{}", self.line, self.column)
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Warning => write!(f, "warning"),
+            Self::Error => write!(f, "error"),
+        }
+    }
+}
+
+
+/// A message about a piece of SML source.
+///
+/// Displaying a diagnostic renders it in the style of rustc, with the offending line underlined:
+/// ```text
+/// error: Unexpected 'end of line', expected ':'
+///  --> machine.sml:2:14
+///   |
+/// 2 |     when true
+///   |              ^
+///   = hint: headers (`state`, `when`, `always`, ...) end with a colon
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: SourceSpan,
+    pub hint: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn error<M: ToString>(src: &str, range: Range<usize>, message: M) -> Self {
+        Self { severity: Severity::Error, message: message.to_string(), span: SourceSpan::new(src, range), hint: None }
+    }
+
+    pub fn warning<M: ToString>(src: &str, range: Range<usize>, message: M) -> Self {
+        Self { severity: Severity::Warning, ..Self::error(src, range, message) }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    pub fn with_hint<H: ToString>(mut self, hint: H) -> Self {
+        self.hint = Some(hint.to_string());
+        self
+    }
+
+    pub fn with_file<F: ToString>(mut self, file: F) -> Self {
+        self.span.file = Some(file.to_string());
+        self
+    }
+
+    pub fn render(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let span = &self.span;
+        let lineno = span.line.to_string();
+        let pad = " ".repeat(lineno.len());
+
+        // Underline the span, or just its first line if it covers several.
+        let line = &span.source_line;
+        let start = line.char_indices().nth(span.column - 1).map(|(i, _)| i).unwrap_or(line.len());
+        let end = (start + span.range.len()).min(line.len());
+        let width = line.get(start..end).map(|s| s.chars().count()).unwrap_or(0).max(1);
+        let indent: String = line[..start].chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        writeln!(f, "{}: {}", self.severity, self.message)?;
+        writeln!(f, "{pad}--> {span}")?;
+        writeln!(f, "{pad} |")?;
+        writeln!(f, "{lineno} | {}", span.source_line)?;
+        write!(f, "{pad} | {indent}{}", "^".repeat(width))?;
+        if let Some(hint) = &self.hint {
+            write!(f, "\n{pad} = hint: {hint}")?;
+        }
+        Ok(())
+    }
+}
+
+
+/// Where in a machine a runtime error happened.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuntimeLocation {
+    pub line: usize,
+    pub state: String,
+
+    /// The block being run: `default head`, `head`, or the header of a branch (`when ...`).
+    pub block: String,
+}
+
+impl fmt::Display for RuntimeLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {} in state {} ({})", self.line, self.state, self.block)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_span_position() {
+        let src = "state A:\n    when true\n";
+        let span = SourceSpan::new(src, 22..23);
+        assert_eq!(span.line, 2);
+        assert_eq!(span.column, 14);
+        assert_eq!(span.source_line, "    when true");
+    }
+
+    #[test]
+    fn test_render() {
+        let src = "state A:\n    always:\n        outputs.x = 1)\n";
+        let d = Diagnostic::error(src, 42..43, "Unmatched closing bracket ')'")
+            .with_hint("remove it")
+            .with_file("a.sml");
+        let expected = [
+            "error: Unmatched closing bracket ')'",
+            " --> a.sml:3:22",
+            "  |",
+            "3 |         outputs.x = 1)",
+            "  |                      ^",
+            "  = hint: remove it",
+        ].join("\n");
+        assert_eq!(d.render(), expected);
+    }
+
+    #[test]
+    fn test_render_underlines_span() {
+        let src = "foo bar baz";
+        let d = Diagnostic::error(src, 4..7, "bad bar");
+        assert!(d.render().ends_with("1 | foo bar baz\n  |     ^^^"));
+    }
+}
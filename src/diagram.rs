@@ -0,0 +1,216 @@
+//! Diagrams of state machines, as Graphviz DOT or Mermaid flowcharts.
+use std::fmt::Write;
+
+use crate::expression::Expression;
+use crate::state::{Branch, State, StateOp};
+use crate::value::Value;
+use crate::StateMachine;
+
+
+/// Edge label for a branch: its condition, or the keyword for `always`, `normally` and
+/// `otherwise` branches (which have a condition of `true`).
+fn edge_label(branch: &Branch) -> String {
+    match &branch.condition {
+        Expression::Value(Value::Bool(true)) if !branch.label.starts_with("when") => branch.label.clone(),
+        condition => condition.to_string(),
+    }
+}
+
+/// An edge of the diagram: source state, target (`None` for `end`), label, and whether it is the
+/// default branch.
+struct Edge<'a> {
+    from: &'a str,
+    to: Option<&'a str>,
+    label: String,
+    is_default: bool,
+}
+
+impl StateMachine {
+    /// States in the order they are defined, so diagrams come out the same every time.
+    fn states_in_order(&self) -> Vec<&State> {
+        let mut states: Vec<&State> = self.states().values().map(|s| &**s).collect();
+        states.sort_by_key(|state| state.span().start);
+        states
+    }
+
+    fn edges(&self) -> Vec<Edge<'_>> {
+        let mut edges = Vec::new();
+        for state in self.states_in_order() {
+            for (i, branch) in state.branches().iter().enumerate() {
+                let to = match &branch.state_op {
+                    StateOp::ChangeTo(transition) => Some(transition.target.as_str()),
+                    StateOp::End => None,
+                    StateOp::Stay => continue,
+                };
+                let label = edge_label(branch);
+                let is_default = state.default_branch() == Some(i);
+                edges.push(Edge { from: state.name(), to, label, is_default });
+            }
+        }
+        edges
+    }
+
+    /// Render the machine as a Graphviz DOT digraph, with one node per state and one edge per
+    /// branch that changes state. Branches which `end` the machine go to a terminal node, and the
+    /// default branch of each state is drawn bold.
+    /// ```
+    /// let sm = shakemyleg::compile(r#"
+    /// state A:
+    ///     when inputs.x > 1:
+    ///         end
+    /// "#).unwrap();
+    /// assert!(sm.to_dot().contains(r#""A" -> __end [label="inputs.x > 1"];"#));
+    /// ```
+    pub fn to_dot(&self) -> String {
+        let quote = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""));
+
+        let mut dot = String::from("digraph StateMachine {\n");
+        dot.push_str("    node [shape=box];\n");
+        dot.push_str("    __start [shape=point];\n");
+        let edges = self.edges();
+        if edges.iter().any(|edge| edge.to.is_none()) {
+            dot.push_str("    __end [shape=doublecircle, label=\"end\"];\n");
+        }
+        for state in self.states_in_order() {
+            writeln!(dot, "    {};", quote(state.name())).unwrap();
+        }
+        for initial_state in self.initial_states() {
+            writeln!(dot, "    __start -> {};", quote(initial_state)).unwrap();
+        }
+        for edge in edges {
+            let to = edge.to.map(quote).unwrap_or_else(|| "__end".to_string());
+            let style = if edge.is_default { ", style=bold" } else { "" };
+            writeln!(dot, "    {} -> {to} [label={}{style}];", quote(edge.from), quote(&edge.label)).unwrap();
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Render the machine as a Mermaid flowchart, laid out like [StateMachine::to_dot]. Default
+    /// branches are drawn as thick links.
+    /// ```
+    /// let sm = shakemyleg::compile(r#"
+    /// state A:
+    ///     when inputs.x > 1:
+    ///         end
+    /// "#).unwrap();
+    /// assert!(sm.to_mermaid().contains(r#"state_A -->|"inputs.x > 1"| end_"#));
+    /// ```
+    pub fn to_mermaid(&self) -> String {
+        // Node ids are prefixed, as some words (`end`, for one) are reserved by Mermaid, and can't
+        // have the dots of nested state names.
+        let id = |name: &str| format!("state_{}", name.replace('.', "__"));
+        let quote = |s: &str| format!("\"{}\"", s.replace('"', "#quot;"));
+
+        let mut mermaid = String::from("flowchart TD\n");
+        mermaid.push_str("    start_(( ))\n");
+        let edges = self.edges();
+        if edges.iter().any(|edge| edge.to.is_none()) {
+            mermaid.push_str("    end_(((end)))\n");
+        }
+        for state in self.states_in_order() {
+            writeln!(mermaid, "    {}[{}]", id(state.name()), quote(state.name())).unwrap();
+        }
+        for initial_state in self.initial_states() {
+            writeln!(mermaid, "    start_ --> {}", id(initial_state)).unwrap();
+        }
+        for edge in edges {
+            let to = edge.to.map(id).unwrap_or_else(|| "end_".to_string());
+            let arrow = if edge.is_default { "==>" } else { "-->" };
+            writeln!(mermaid, "    {} {arrow}|{}| {to}", id(edge.from), quote(&edge.label)).unwrap();
+        }
+        mermaid
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::compile;
+
+    const SRC: &str = r#"
+state A:
+    when inputs.s == "go":
+        changeto B
+    otherwise:
+        default
+        end
+state B:
+    always:
+        outputs.x = 1
+"#;
+
+    #[test]
+    fn test_to_dot() {
+        let dot = compile(SRC).unwrap().to_dot();
+        let expected = [
+            "digraph StateMachine {",
+            "    node [shape=box];",
+            "    __start [shape=point];",
+            "    __end [shape=doublecircle, label=\"end\"];",
+            "    \"A\";",
+            "    \"B\";",
+            "    __start -> \"A\";",
+            "    \"A\" -> \"B\" [label=\"inputs.s == \\\"go\\\"\"];",
+            "    \"A\" -> __end [label=\"otherwise\", style=bold];",
+            "}",
+            "",
+        ].join("\n");
+        assert_eq!(dot, expected);
+    }
+
+    #[test]
+    fn test_to_mermaid() {
+        let mermaid = compile(SRC).unwrap().to_mermaid();
+        let expected = [
+            "flowchart TD",
+            "    start_(( ))",
+            "    end_(((end)))",
+            "    state_A[\"A\"]",
+            "    state_B[\"B\"]",
+            "    start_ --> state_A",
+            "    state_A -->|\"inputs.s == #quot;go#quot;\"| state_B",
+            "    state_A ==>|\"otherwise\"| end_",
+            "",
+        ].join("\n");
+        assert_eq!(mermaid, expected);
+    }
+
+    #[test]
+    fn test_no_end_node() {
+        const SRC: &str = r#"
+state A:
+    always:
+        changeto B
+state B:
+    always:
+        changeto A
+"#;
+        let sm = compile(SRC).unwrap();
+        assert!(!sm.to_dot().contains("__end"));
+        assert!(!sm.to_mermaid().contains("end_"));
+    }
+
+    // A branch that can never run is still drawn, so its `end` node must be too.
+    const DEAD_END: &str = r#"
+state A:
+    when false:
+        end
+    otherwise:
+        changeto A
+"#;
+
+    #[test]
+    fn test_dead_end_dot() {
+        let dot = compile(DEAD_END).unwrap().to_dot();
+        assert!(dot.contains("    __end [shape=doublecircle, label=\"end\"];"));
+        assert!(dot.contains("    \"A\" -> __end [label=\"false\"];"));
+    }
+
+    #[test]
+    fn test_dead_end_mermaid() {
+        let mermaid = compile(DEAD_END).unwrap().to_mermaid();
+        assert!(mermaid.contains("    end_(((end)))"));
+        assert!(mermaid.contains("    state_A -->|\"false\"| end_"));
+    }
+}
@@ -1,5 +1,7 @@
 use std::io;
 
+use crate::diagnostic::{Diagnostic, RuntimeLocation};
+
 #[allow(non_camel_case_types)]
 #[derive(thiserror::Error, Debug)]
 pub enum SML_Error {
@@ -19,6 +21,9 @@ pub enum SML_Error {
     #[error("SML Syntax error error. {0}")]
     BadOperation(String),
 
+    #[error("Both {0} and {1} set outputs.{2}.")]
+    OutputConflict(String, String, String),
+
     #[error("Nonexistant state {0}")]
     NonexistantState(String),
 
@@ -31,12 +36,39 @@ pub enum SML_Error {
     #[error("Input store is immutable and cannot be written to.")]
     InputsWriteError,
 
-    #[error("Syntax error: {0}")]
-    SyntaxError(String),
+    #[error("{0}")]
+    SyntaxError(Box<Diagnostic>),
 
     #[error("Compiler error (email the dev!): {0}")]
     CompilerError(String),
 
+    #[error("{error} (at {location})")]
+    RuntimeError { error: Box<SML_Error>, location: Box<RuntimeLocation> },
+
+}
+
+impl SML_Error {
+    /// The underlying error, with any runtime location stripped off.
+    pub fn inner(&self) -> &SML_Error {
+        match self {
+            Self::RuntimeError { error, .. } => error.inner(),
+            e => e,
+        }
+    }
+
+    /// Attach a runtime location, unless the error already has one.
+    pub(crate) fn at(self, location: RuntimeLocation) -> Self {
+        match self {
+            e @ Self::RuntimeError { .. } => e,
+            e => Self::RuntimeError { error: Box::new(e), location: Box::new(location) },
+        }
+    }
+}
+
+impl From<Diagnostic> for SML_Error {
+    fn from(diagnostic: Diagnostic) -> Self {
+        Self::SyntaxError(Box::new(diagnostic))
+    }
 }
 
 
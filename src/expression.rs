@@ -1,46 +1,251 @@
+use std::fmt;
+
 use json::JsonValue;
 
 use crate::error::{SML_Error, SML_Result};
 use crate::value::Value;
+use crate::function::Function;
 use crate::identifier::Identifier;
-use crate::operation::{UnaryOperation, BinaryOperation};
+use crate::lexer::escape;
+use crate::operation::{UnaryOperation, BinaryOperation, CONDITIONAL_PRECEDENCE, UNARY_PRECEDENCE};
+
 
+/// A piece of an f-string: text, or an expression to interpolate.
+#[derive(Clone, Debug)]
+pub enum FormatPart {
+    Literal(String),
+    Expression(Expression),
+}
 
 #[derive(Clone, Debug)]
 pub enum Expression {
     Value(Value),
+
+    /// A number written other than as it prints, like `0x1F`, `1_000` or `2.50`, kept with its
+    /// source text so that `sml fmt` prints it back unchanged.
+    Number(f64, String),
+
     Identifier(Identifier),
     Unary(UnaryOperation, Box<Expression>),
     Binary(BinaryOperation, Box<Expression>, Box<Expression>),
+    Call(Function, Vec<Expression>),
+    Format(Vec<FormatPart>),
+
+    /// A list literal with items worked out at runtime, e.g. `[inputs.x, -1]`. Lists of only
+    /// literal values are parsed as a `Value`.
+    List(Vec<Expression>),
+
+    /// An object literal, e.g. `{"x": inputs.x}`, with its keys in the order written. Values are
+    /// worked out at runtime, and later keys win over earlier ones.
+    Object(Vec<(String, Expression)>),
+
+    /// Indexing into the result of an expression (other than an identifier, whose path includes
+    /// any indexing), e.g. `sort(inputs.l)[0]`.
+    Index(Box<Expression>, Box<Expression>),
+
+    /// `condition ? if_true : if_false`; only the branch chosen is evaluated.
+    Conditional(Box<Expression>, Box<Expression>, Box<Expression>),
 }
 
 impl Expression {
-    pub fn evaluate(&self, i: &JsonValue, o: &mut JsonValue, g: &mut JsonValue) -> SML_Result<Value> {
+    /// Whether the expression evaluates to the same value every time, i.e. reads no identifiers.
+    /// Built-in functions always give the same result for the same arguments; native ones might
+    /// not.
+    pub fn is_constant(&self) -> bool {
+        match self {
+            Self::Value(_) | Self::Number(..) => true,
+            Self::Identifier(_) => false,
+            Self::Unary(_, operand) => operand.is_constant(),
+            Self::Binary(_, left, right) => left.is_constant() && right.is_constant(),
+            Self::Call(function, args) => !function.is_native() && args.iter().all(Expression::is_constant),
+            Self::Index(base, index) => base.is_constant() && index.is_constant(),
+            Self::List(items) => items.iter().all(Expression::is_constant),
+            Self::Object(entries) => entries.iter().all(|(_, item)| item.is_constant()),
+            Self::Conditional(condition, if_true, if_false) => {
+                condition.is_constant() && if_true.is_constant() && if_false.is_constant()
+            },
+            Self::Format(parts) => parts.iter().all(|part| match part {
+                FormatPart::Literal(_) => true,
+                FormatPart::Expression(expression) => expression.is_constant(),
+            }),
+        }
+    }
+
+    /// Store `value` to the identifier this expression names.
+    pub(crate) fn assign(&self, i: &JsonValue, o: &mut JsonValue, g: &mut JsonValue, l: &mut JsonValue, value: &Value) -> SML_Result<()> {
+        match self {
+            Self::Identifier(identifier) => identifier.set(i, o, g, l, value),
+            _ => Err(SML_Error::BadOperation(format!("Can only assign to a name, not `{self}`."))),
+        }
+    }
+
+    pub fn evaluate(&self, i: &JsonValue, o: &mut JsonValue, g: &mut JsonValue, l: &mut JsonValue) -> SML_Result<Value> {
         let rv = match self {
             Self::Value(value) => value.clone(),
-            Self::Identifier(identifier) => identifier.get(i, o, g)?,
+            Self::Number(n, _) => Value::Number(*n),
+            Self::Identifier(identifier) => identifier.get(i, o, g, l)?,
             Self::Unary(op, operand) => {
-                let operand = operand.evaluate(i, o, g)?;
-                op.apply(&operand)?
+                let value = op.apply(&operand.evaluate(i, o, g, l)?)?;
+                if op.is_postfix() {
+                    operand.assign(i, o, g, l, &value)?;
+                }
+                value
+            },
+            Self::Binary(BinaryOperation::Assign, left, right) => {
+                let right = right.evaluate(i, o, g, l)?;
+                left.assign(i, o, g, l, &right)?;
+                right
+            },
+            Self::Binary(BinaryOperation::CompoundAssign(op), left, right) => {
+                let value = op.apply(&left.evaluate(i, o, g, l)?, &right.evaluate(i, o, g, l)?)?;
+                left.assign(i, o, g, l, &value)?;
+                value
+            },
+            // `&&` and `||` only evaluate their right operand if they need to, so the left can
+            // guard it.
+            Self::Binary(BinaryOperation::And, left, right) => {
+                Value::Bool(left.evaluate(i, o, g, l)?.as_bool() && right.evaluate(i, o, g, l)?.as_bool())
+            },
+            Self::Binary(BinaryOperation::Or, left, right) => {
+                Value::Bool(left.evaluate(i, o, g, l)?.as_bool() || right.evaluate(i, o, g, l)?.as_bool())
             },
             Self::Binary(op, left, right) => {
-                let right = right.evaluate(i, o, g)?;
-                if matches!(op, BinaryOperation::Assign) {
-                    match &**left {
-                        Self::Identifier(identifier) => {
-                            identifier.set(o, g, &right)?
-                        },
-                        _ => { return Err(SML_Error::BadOperation(format!("can only assign to identifier, got {left:?}"))); }
-                    }
-                    right
+                let left = left.evaluate(i, o, g, l)?;
+                let right = right.evaluate(i, o, g, l)?;
+                op.apply(&left, &right)?
+            },
+            Self::Call(function, args) => {
+                let args = args.iter()
+                    .map(|arg| arg.evaluate(i, o, g, l))
+                    .collect::<SML_Result<Vec<_>>>()?;
+                function.call(&args)?
+            },
+            Self::Index(base, index) => {
+                let base = base.evaluate(i, o, g, l)?;
+                base.index(&index.evaluate(i, o, g, l)?)?
+            },
+            Self::List(items) => {
+                let items = items.iter()
+                    .map(|item| item.evaluate(i, o, g, l))
+                    .collect::<SML_Result<Vec<_>>>()?;
+                Value::List(items)
+            },
+            Self::Object(entries) => {
+                let entries = entries.iter()
+                    .map(|(key, item)| Ok((key.clone(), item.evaluate(i, o, g, l)?)))
+                    .collect::<SML_Result<_>>()?;
+                Value::Object(entries)
+            },
+            Self::Conditional(condition, if_true, if_false) => {
+                if condition.evaluate(i, o, g, l)?.as_bool() {
+                    if_true.evaluate(i, o, g, l)?
                 }
                 else {
-                    let left = left.evaluate(i, o, g)?;
-                    op.apply(&left, &right)?
+                    if_false.evaluate(i, o, g, l)?
                 }
-            }
+            },
+            Self::Format(parts) => {
+                let mut s = String::new();
+                for part in parts {
+                    match part {
+                        FormatPart::Literal(text) => s.push_str(text),
+                        FormatPart::Expression(expression) => s.push_str(&expression.evaluate(i, o, g, l)?.as_string()),
+                    }
+                }
+                Value::String(s)
+            },
         };
 
         Ok(rv)
     }
 }
+
+
+/// Binding strength of an expression as an operand, as in the parser; anything but an operator
+/// is atomic.
+fn binding(expression: &Expression) -> u8 {
+    match expression {
+        Expression::Binary(op, ..) => op.precedence(),
+        Expression::Conditional(..) => CONDITIONAL_PRECEDENCE,
+        Expression::Unary(op, _) if op.is_postfix() => 0,
+        Expression::Unary(..) => UNARY_PRECEDENCE,
+        _ => u8::MAX,
+    }
+}
+
+/// Expressions are displayed as SML source, with brackets only where they are needed.
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Value(value) => write!(f, "{value}"),
+            Self::Number(_, text) => write!(f, "{text}"),
+            Self::Identifier(identifier) => write!(f, "{identifier}"),
+            Self::Unary(op, operand) if op.is_postfix() => write!(f, "{operand}{op}"),
+            Self::Unary(op, operand) => {
+                // Nested prefix operators are bracketed too, so that `-(-x)` isn't read as `--`.
+                if binding(operand) <= UNARY_PRECEDENCE {
+                    write!(f, "{op}({operand})")
+                }
+                else {
+                    write!(f, "{op}{operand}")
+                }
+            },
+            Self::Binary(op, left, right) => {
+                // An operand at the same level needs brackets on the side the operator doesn't
+                // associate to.
+                let prec = op.precedence();
+                let (left_prec, right_prec) = if op.is_right_associative() { (prec + 1, prec) } else { (prec, prec + 1) };
+                if binding(left) < left_prec {
+                    write!(f, "({left})")?;
+                }
+                else {
+                    write!(f, "{left}")?;
+                }
+                write!(f, " {op} ")?;
+                if binding(right) < right_prec {
+                    write!(f, "({right})")
+                }
+                else {
+                    write!(f, "{right}")
+                }
+            },
+            Self::Conditional(condition, if_true, if_false) => {
+                // `?:` is right-associative: `a ? b : c ? d : e` needs no brackets.
+                if binding(condition) <= CONDITIONAL_PRECEDENCE {
+                    write!(f, "({condition}) ? ")?;
+                }
+                else {
+                    write!(f, "{condition} ? ")?;
+                }
+                let bracket = |e: &Expression| if binding(e) < CONDITIONAL_PRECEDENCE { format!("({e})") } else { e.to_string() };
+                write!(f, "{} : {}", bracket(if_true), bracket(if_false))
+            },
+            Self::Call(function, args) => {
+                let args: Vec<_> = args.iter().map(|arg| arg.to_string()).collect();
+                write!(f, "{}({})", function.name(), args.join(", "))
+            },
+            Self::Index(base, index) => match &**base {
+                Self::Unary(..) | Self::Binary(..) | Self::Conditional(..) => write!(f, "({base})[{index}]"),
+                _ => write!(f, "{base}[{index}]"),
+            },
+            Self::List(items) => {
+                let items: Vec<_> = items.iter().map(|item| item.to_string()).collect();
+                write!(f, "[{}]", items.join(", "))
+            },
+            Self::Object(entries) => {
+                let entries: Vec<_> = entries.iter().map(|(key, item)| format!("\"{}\": {item}", escape(key))).collect();
+                write!(f, "{{{}}}", entries.join(", "))
+            },
+            Self::Format(parts) => {
+                write!(f, "f\"")?;
+                for part in parts {
+                    match part {
+                        FormatPart::Literal(text) => write!(f, "{}", escape(text).replace('{', "{{").replace('}', "}}"))?,
+                        FormatPart::Expression(expression) => write!(f, "{{{expression}}}")?,
+                    }
+                }
+                write!(f, "\"")
+            },
+        }
+    }
+}
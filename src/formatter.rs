@@ -0,0 +1,337 @@
+//! Printing SML source in a canonical layout.
+//!
+//! Source is parsed to its syntax tree, then printed back: blocks are indented by four spaces,
+//! expressions are spaced as by their `Display` impl (which keeps numbers as written, so `0x1F`
+//! stays `0x1F`), and `state` blocks (and the `default head`) are separated by one blank line.
+//! Otherwise, a single blank line is kept wherever the source had one or more.
+//!
+//! Comments are kept in a side table by the lexer, and printed back in place. A comment on a line
+//! of its own is printed before the next line of code, at its indentation; a comment after code
+//! stays at the end of that line.
+use std::collections::VecDeque;
+
+use crate::ast::{Program, Item, StateDecl, StateItem, BranchKind, Line, LineKind};
+use crate::diagnostic::Diagnostic;
+use crate::error::SML_Result;
+use crate::lexer::{lex_with_comments, Comment, Span};
+use crate::parser::parse;
+
+
+const INDENT: &str = "    ";
+
+
+struct Printer<'a> {
+    src: &'a str,
+    comments: VecDeque<Comment>,
+    out: String,
+
+    /// End of the last piece of source printed.
+    last_end: usize,
+}
+
+impl<'a> Printer<'a> {
+    /// Whether there is a blank line in the source between `from` and `to`.
+    fn blank_between(&self, from: usize, to: usize) -> bool {
+        let between: Vec<_> = self.src[from..to.max(from)].split('\n').collect();
+        between.len() > 2 && between[1..between.len() - 1].iter().any(|l| l.trim().is_empty())
+    }
+
+    fn blank_line(&mut self) {
+        if !self.out.is_empty() && !self.out.ends_with("\n\n") {
+            self.out.push('\n');
+        }
+    }
+
+    /// Print a line of code, preceded by any comments before it (or inside it, for code split over
+    /// several lines) and followed by a comment at the end of its line, if there is one.
+    fn line(&mut self, depth: usize, text: &str, span: &Span, force_blank: bool) {
+        let mut blank = force_blank;
+        while self.comments.front().is_some_and(|c| c.span.start < span.end) {
+            let comment = self.comments.pop_front().unwrap();
+            if blank || self.blank_between(self.last_end, comment.span.start) {
+                self.blank_line();
+                blank = false;
+            }
+            self.out.push_str(&INDENT.repeat(depth));
+            self.out.push_str(&comment.text);
+            self.out.push('\n');
+            self.last_end = comment.span.end;
+        }
+
+        if blank || self.blank_between(self.last_end, span.start) {
+            self.blank_line();
+        }
+        self.out.push_str(&INDENT.repeat(depth));
+        self.out.push_str(text);
+        self.last_end = span.end;
+
+        let eol = self.src[span.end..].find('\n').map(|i| span.end + i).unwrap_or(self.src.len());
+        if self.comments.front().is_some_and(|c| c.span.start < eol) {
+            let comment = self.comments.pop_front().unwrap();
+            self.out.push_str("  ");
+            self.out.push_str(&comment.text);
+            self.last_end = comment.span.end;
+        }
+        self.out.push('\n');
+    }
+
+    fn block(&mut self, depth: usize, lines: &[Line]) {
+        for line in lines {
+            let text = match &line.kind {
+                LineKind::If(arms) => {
+                    for (n, arm) in arms.iter().enumerate() {
+                        let header = match &arm.condition {
+                            Some(condition) if n == 0 => format!("if {condition}:"),
+                            Some(condition) => format!("elif {condition}:"),
+                            None => "else:".to_string(),
+                        };
+                        self.line(depth, &header, &arm.span, false);
+                        self.block(depth + 1, &arm.lines);
+                    }
+                    continue;
+                },
+                LineKind::For(for_loop) => {
+                    let header = format!("for {} in {}:", for_loop.target, for_loop.list);
+                    self.line(depth, &header, &for_loop.span, false);
+                    self.block(depth + 1, &for_loop.lines);
+                    continue;
+                },
+                LineKind::Expr(expression) => expression.to_string(),
+                LineKind::ChangeTo(name, span, actions) if !actions.is_empty() => {
+                    // Keep a single action on the line of the `changeto` if that is where it was.
+                    if let [Line { kind: LineKind::Expr(action), span: action_span }] = actions.as_slice() {
+                        if !self.src[span.end..action_span.start].contains('\n') {
+                            let text = format!("changeto {name} with {action}");
+                            self.line(depth, &text, &(line.span.start..action_span.end), false);
+                            continue;
+                        }
+                    }
+                    self.line(depth, &format!("changeto {name} with:"), span, false);
+                    self.block(depth + 1, actions);
+                    continue;
+                },
+                LineKind::ChangeTo(name, _, _) => format!("changeto {name}"),
+                LineKind::End => "end".to_string(),
+                LineKind::Stay => "stay".to_string(),
+                LineKind::Default => "default".to_string(),
+                LineKind::Error => self.src[line.span.clone()].trim().to_string(),
+            };
+            self.line(depth, &text, &line.span, false);
+        }
+    }
+
+    fn state(&mut self, depth: usize, state: &StateDecl, force_blank: bool) {
+        self.line(depth, &format!("state {}:", state.name), &state.span, force_blank);
+        for state_item in &state.items {
+            match state_item {
+                StateItem::Head(lines, span) => {
+                    self.line(depth + 1, "head:", span, false);
+                    self.block(depth + 2, lines);
+                },
+                StateItem::OnEnter(lines, span) => {
+                    self.line(depth + 1, "on enter:", span, false);
+                    self.block(depth + 2, lines);
+                },
+                StateItem::OnExit(lines, span) => {
+                    self.line(depth + 1, "on exit:", span, false);
+                    self.block(depth + 2, lines);
+                },
+                StateItem::Branch(branch) => {
+                    let header = match &branch.kind {
+                        BranchKind::When(condition) => format!("when {condition}:"),
+                        BranchKind::Always => "always:".to_string(),
+                        BranchKind::Normally => "normally:".to_string(),
+                        BranchKind::Otherwise => "otherwise:".to_string(),
+                    };
+                    self.line(depth + 1, &header, &branch.span, false);
+                    self.block(depth + 2, &branch.lines);
+                },
+                StateItem::State(child) => self.state(depth + 1, child, false),
+                StateItem::Error => (),
+            }
+        }
+    }
+
+    fn program(&mut self, program: &Program) {
+        for item in &program.items {
+            match item {
+                Item::DefaultHead(lines, span) => {
+                    self.line(0, "default head:", span, true);
+                    self.block(1, lines);
+                },
+                Item::State(state) => self.state(0, state, true),
+                Item::Parallel(regions, span) => {
+                    self.line(0, "parallel:", span, true);
+                    for region in regions {
+                        self.line(1, &format!("region {}:", region.name), &region.span, false);
+                        for state in region.states.iter().flatten() {
+                            self.state(2, state, false);
+                        }
+                    }
+                },
+                Item::Error => (),
+            }
+        }
+
+        // Anything left is after the last line of code.
+        for comment in std::mem::take(&mut self.comments) {
+            if self.blank_between(self.last_end, comment.span.start) {
+                self.blank_line();
+            }
+            self.out.push_str(&comment.text);
+            self.out.push('\n');
+            self.last_end = comment.span.end;
+        }
+    }
+}
+
+
+/// Format SML source in the canonical layout (see the [module docs](self)). Formatting is
+/// idempotent: formatting formatted source gives it back unchanged.
+///
+/// The source must parse, but need not compile; the first syntax error is returned if it doesn't.
+/// ```
+/// let src = "state A:\n\twhen inputs.x>1:   # big\n\t\tend\n\n\n";
+/// assert_eq!(shakemyleg::format(src).unwrap(), "state A:\n    when inputs.x > 1:  # big\n        end\n");
+/// ```
+pub fn format(src: &str) -> SML_Result<String> {
+    let first_error = |errors: Vec<crate::lexer::ParseError>| -> Diagnostic {
+        errors[0].clone().into_diagnostic(src)
+    };
+
+    let (tokens, comments) = lex_with_comments(src).map_err(first_error)?;
+    let (program, errors) = parse(tokens, src.len(), None);
+    if !errors.is_empty() {
+        return Err(first_error(errors).into());
+    }
+
+    let mut printer = Printer { src, comments: comments.into(), out: String::new(), last_end: 0 };
+    if let Some(program) = program {
+        printer.program(&program);
+    }
+    Ok(printer.out)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSY: &str = r#"
+
+# A machine
+default head:
+  globals.n = globals.n+1
+state A:
+  # check x
+  when inputs.x>1&&inputs.y  ==  "a  b":   # both
+      outputs.l = [1,
+ 2]
+
+
+      changeto B
+  otherwise:
+      stay
+state B:
+	always:
+		end
+
+# fin
+"#;
+
+    const TIDY: &str = r#"# A machine
+default head:
+    globals.n = globals.n + 1
+
+state A:
+    # check x
+    when inputs.x > 1 && inputs.y == "a  b":  # both
+        outputs.l = [1, 2]
+
+        changeto B
+    otherwise:
+        stay
+
+state B:
+    always:
+        end
+
+# fin
+"#;
+
+    #[test]
+    fn test_format() {
+        assert_eq!(format(MESSY).unwrap(), TIDY);
+    }
+
+    #[test]
+    fn test_format_idempotent() {
+        assert_eq!(format(TIDY).unwrap(), TIDY);
+    }
+
+    #[test]
+    fn test_format_keeps_meaning() {
+        let sm = crate::compile(&format(MESSY).unwrap());
+        assert!(sm.is_ok());
+    }
+
+    #[test]
+    fn test_format_native_calls() {
+        // Native functions aren't known when formatting, so calls are printed without resolving.
+        let src = "state A:\n    always:\n        outputs.x = lookup( inputs.x,1 )\n";
+        assert_eq!(format(src).unwrap(), "state A:\n    always:\n        outputs.x = lookup(inputs.x, 1)\n");
+    }
+
+    #[test]
+    fn test_format_blocks() {
+        let src = "state A:\n  always:\n    for locals.x in inputs.xs:\n      if locals.x>1:  # big\n        outputs.n+=1\n      else:\n        outputs.m  = 1\n";
+        let tidy = "state A:\n    always:\n        for locals.x in inputs.xs:\n            if locals.x > 1:  # big\n                outputs.n += 1\n            else:\n                outputs.m = 1\n";
+        assert_eq!(format(src).unwrap(), tidy);
+        assert_eq!(format(tidy).unwrap(), tidy);
+    }
+
+    #[test]
+    fn test_format_transition_actions() {
+        let src = "state A:\n  always:\n    changeto A with globals.n+=1  # count\n    changeto A  with :\n      globals.n=0\n";
+        let tidy = "state A:\n    always:\n        changeto A with globals.n += 1  # count\n        changeto A with:\n            globals.n = 0\n";
+        assert_eq!(format(src).unwrap(), tidy);
+        assert_eq!(format(tidy).unwrap(), tidy);
+    }
+
+    #[test]
+    fn test_format_nested_states() {
+        let src = "state A:\n  when inputs.stop:\n    changeto  B\n  state A1:\n    always:\n      changeto A . A2\n  state A2:\n    head:\n      outputs.x=1\nstate B:\n  always:\n    end\n";
+        let tidy = "state A:\n    when inputs.stop:\n        changeto B\n    state A1:\n        always:\n            changeto A.A2\n    state A2:\n        head:\n            outputs.x = 1\n\nstate B:\n    always:\n        end\n";
+        assert_eq!(format(src).unwrap(), tidy);
+        assert_eq!(format(tidy).unwrap(), tidy);
+    }
+
+    #[test]
+    fn test_format_parallel() {
+        let src = "default head:\n  globals.n+=1\nparallel:\n  region A:\n    state A1:\n      always:\n        stay\n  region B:\n    state B1:\n      always:\n        end\n";
+        let tidy = "default head:\n    globals.n += 1\n\nparallel:\n    region A:\n        state A1:\n            always:\n                stay\n    region B:\n        state B1:\n            always:\n                end\n";
+        assert_eq!(format(src).unwrap(), tidy);
+        assert_eq!(format(tidy).unwrap(), tidy);
+    }
+
+    #[test]
+    fn test_format_actions() {
+        let src = "state A:\n  on  enter:\n    globals.n=0\n  on exit :\n    outputs.done=true\n  always:\n    end\n";
+        let tidy = "state A:\n    on enter:\n        globals.n = 0\n    on exit:\n        outputs.done = true\n    always:\n        end\n";
+        assert_eq!(format(src).unwrap(), tidy);
+    }
+
+    #[test]
+    fn test_format_number_literals() {
+        let src = "state A:\n  when inputs.x&0x1F>0b1010:\n    outputs.l=[1_000,-1e-3, 2.50]\n    outputs.o={\"b\": 0x2, \"a\": 0b1}\n    changeto A with globals.n+=0XFF\n";
+        let tidy = "state A:\n    when inputs.x & 0x1F > 0b1010:\n        outputs.l = [1_000, -1e-3, 2.50]\n        outputs.o = {\"b\": 0x2, \"a\": 0b1}\n        changeto A with globals.n += 0XFF\n";
+        assert_eq!(format(src).unwrap(), tidy);
+        assert_eq!(format(tidy).unwrap(), tidy);
+    }
+
+    #[test]
+    fn test_format_bad_source() {
+        assert!(format("state A:\n  always:\n    outputs.x = (1\n").is_err());
+        assert!(format("state A:\n  when inputs.x >:\n    end\n").is_err());
+    }
+}
@@ -0,0 +1,477 @@
+//! Functions callable from SML expressions: the built-in standard library, and native functions
+//! registered by the host program.
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::error::{SML_Error, SML_Result};
+use crate::operation::approx_equal;
+use crate::value::{resolve_index, Value};
+
+
+/// Signature of the Rust side of a function: takes the evaluated arguments.
+pub type FunctionBody = dyn Fn(&[Value]) -> SML_Result<Value> + Send + Sync;
+
+/// A function, resolved by name when an expression is parsed.
+#[derive(Clone)]
+pub struct Function {
+    name: String,
+    min_args: usize,
+
+    /// Maximum number of arguments, or `None` for no limit.
+    max_args: Option<usize>,
+    body: Arc<FunctionBody>,
+
+    /// Registered by the host, rather than built in. Native functions may not give the same
+    /// result every time, so calls to them are never treated as constant.
+    native: bool,
+}
+
+impl Function {
+    pub fn new<F>(name: &str, min_args: usize, max_args: Option<usize>, body: F) -> Self
+    where
+        F: Fn(&[Value]) -> SML_Result<Value> + Send + Sync + 'static
+    {
+        Self { name: name.to_string(), min_args, max_args, body: Arc::new(body), native: false }
+    }
+
+    /// Placeholder for a call that is parsed but not resolved, e.g. by the formatter, which
+    /// doesn't know which native functions will be registered.
+    pub(crate) fn unresolved(name: &str) -> Self {
+        let message = format!("function {name} was never resolved");
+        Self::new(name, 0, None, move |_| Err(SML_Error::CompilerError(message.clone())))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_native(&self) -> bool {
+        self.native
+    }
+
+    /// Check that the function can be called with `n` arguments, returning an error message if not.
+    pub fn check_arity(&self, n: usize) -> Result<(), String> {
+        let plural = |n: usize| if n == 1 { "argument" } else { "arguments" };
+        let expected = match self.max_args {
+            Some(max) if max == self.min_args => format!("{max} {}", plural(max)),
+            Some(max) => format!("{} to {max} arguments", self.min_args),
+            None => format!("at least {} {}", self.min_args, plural(self.min_args)),
+        };
+        if n < self.min_args || self.max_args.is_some_and(|max| n > max) {
+            return Err(format!("{} takes {expected}, got {n}.", self.name));
+        }
+        Ok(())
+    }
+
+    pub fn call(&self, args: &[Value]) -> SML_Result<Value> {
+        (self.body)(args)
+    }
+}
+
+impl fmt::Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Function({:?})", self.name)
+    }
+}
+
+
+fn number(name: &str, v: &Value) -> SML_Result<f64> {
+    match v {
+        Value::Number(n) => Ok(*n),
+        v => Err(SML_Error::BadOperation(format!("{name} expects numerical arguments, got {v}."))),
+    }
+}
+
+fn string<'a>(name: &str, v: &'a Value) -> SML_Result<&'a str> {
+    match v {
+        Value::String(s) => Ok(s),
+        v => Err(SML_Error::BadOperation(format!("{name} expects string arguments, got {v}."))),
+    }
+}
+
+/// Whole number argument, e.g. an index.
+fn integer(name: &str, v: &Value) -> SML_Result<i64> {
+    match v {
+        Value::Number(n) if n.fract() == 0.0 => Ok(*n as i64),
+        v => Err(SML_Error::BadOperation(format!("{name} expects a whole number, got {v}."))),
+    }
+}
+
+/// Range of a slice of something of length `len`. Negative bounds count from the end, and bounds
+/// past either end are clamped, as in Python.
+fn slice_range(start: i64, end: Option<i64>, len: usize) -> std::ops::Range<usize> {
+    let resolve = |i: i64| {
+        let i = if i < 0 { len as i64 + i } else { i };
+        i.clamp(0, len as i64) as usize
+    };
+    let start = resolve(start);
+    let end = end.map(resolve).unwrap_or(len).max(start);
+    start..end
+}
+
+fn list<'a>(name: &str, v: &'a Value) -> SML_Result<&'a [Value]> {
+    match v {
+        Value::List(items) => Ok(items),
+        v => Err(SML_Error::BadOperation(format!("{name} expects a list, got {v}."))),
+    }
+}
+
+/// A built-in function of one list, giving a list.
+fn list_op(name: &'static str, f: fn(&[Value]) -> SML_Result<Vec<Value>>) -> Function {
+    Function::new(name, 1, Some(1), move |args| Ok(Value::List(f(list(name, &args[0])?)?)))
+}
+
+/// Sort a list of all numbers or all strings.
+fn sorted(items: &[Value]) -> SML_Result<Vec<Value>> {
+    let mut items = items.to_vec();
+    if items.iter().all(|v| matches!(v, Value::Number(_))) {
+        items.sort_by(|a, b| number("sort", a).unwrap().total_cmp(&number("sort", b).unwrap()));
+    }
+    else if items.iter().all(|v| matches!(v, Value::String(_))) {
+        items.sort_by_key(Value::as_string);
+    }
+    else {
+        return Err(SML_Error::BadOperation("sort expects a list of all numbers or all strings.".to_string()));
+    }
+    Ok(items)
+}
+
+/// A built-in function of one string, giving a string.
+fn string_op(name: &'static str, f: fn(&str) -> String) -> Function {
+    Function::new(name, 1, Some(1), move |args| Ok(Value::String(f(string(name, &args[0])?))))
+}
+
+/// Numbers to take the min or max of: the arguments, or the items of a single list argument.
+fn numbers(name: &str, args: &[Value]) -> SML_Result<Vec<f64>> {
+    let items = match args {
+        [Value::List(items)] if !items.is_empty() => items.as_slice(),
+        [Value::List(_)] => { return Err(SML_Error::BadOperation(format!("{name} of an empty list."))); },
+        args => args,
+    };
+    items.iter().map(|v| number(name, v)).collect()
+}
+
+/// A built-in function of one number.
+fn unary(name: &'static str, f: fn(f64) -> f64) -> Function {
+    Function::new(name, 1, Some(1), move |args| Ok(Value::Number(f(number(name, &args[0])?))))
+}
+
+/// Look up a function of the standard library by name.
+pub fn builtin(name: &str) -> Option<Function> {
+    let f = match name {
+        "abs" => unary("abs", f64::abs),
+        "floor" => unary("floor", f64::floor),
+        "ceil" => unary("ceil", f64::ceil),
+        "round" => unary("round", f64::round),
+        "sqrt" => unary("sqrt", f64::sqrt),
+        "exp" => unary("exp", f64::exp),
+        "ln" => unary("ln", f64::ln),
+        "log10" => unary("log10", f64::log10),
+        "sin" => unary("sin", f64::sin),
+        "cos" => unary("cos", f64::cos),
+        "tan" => unary("tan", f64::tan),
+        "asin" => unary("asin", f64::asin),
+        "acos" => unary("acos", f64::acos),
+        "atan" => unary("atan", f64::atan),
+        "atan2" => Function::new("atan2", 2, Some(2), |args| {
+            Ok(Value::Number(number("atan2", &args[0])?.atan2(number("atan2", &args[1])?)))
+        }),
+        "min" => Function::new("min", 1, None, |args| {
+            Ok(Value::Number(numbers("min", args)?.into_iter().fold(f64::INFINITY, f64::min)))
+        }),
+        "max" => Function::new("max", 1, None, |args| {
+            Ok(Value::Number(numbers("max", args)?.into_iter().fold(f64::NEG_INFINITY, f64::max)))
+        }),
+        "clamp" => Function::new("clamp", 3, Some(3), |args| {
+            let x = number("clamp", &args[0])?;
+            let lo = number("clamp", &args[1])?;
+            let hi = number("clamp", &args[2])?;
+            if lo > hi {
+                return Err(SML_Error::BadOperation(format!("clamp lower bound {lo} is above upper bound {hi}.")));
+            }
+            Ok(Value::Number(x.clamp(lo, hi)))
+        }),
+        // Without a tolerance, the same as `a ~= b`.
+        "approx" => Function::new("approx", 2, Some(3), |args| {
+            let a = number("approx", &args[0])?;
+            let b = number("approx", &args[1])?;
+            let Some(tol) = args.get(2) else {
+                return Ok(Value::Bool(approx_equal(a, b)));
+            };
+            let tol = number("approx", tol)?;
+            if tol < 0.0 {
+                return Err(SML_Error::BadOperation(format!("approx tolerance must not be negative, got {tol}.")));
+            }
+            Ok(Value::Bool((a - b).abs() <= tol))
+        }),
+
+        // Strings
+        "len" => Function::new("len", 1, Some(1), |args| match &args[0] {
+            Value::String(s) => Ok(Value::Number(s.chars().count() as f64)),
+            Value::List(items) => Ok(Value::Number(items.len() as f64)),
+            v => Err(SML_Error::BadOperation(format!("len expects a string or list, got {v}."))),
+        }),
+        "upper" => string_op("upper", str::to_uppercase),
+        "lower" => string_op("lower", str::to_lowercase),
+        "trim" => string_op("trim", |s| s.trim().to_string()),
+        "starts_with" => Function::new("starts_with", 2, Some(2), |args| {
+            Ok(Value::Bool(string("starts_with", &args[0])?.starts_with(string("starts_with", &args[1])?)))
+        }),
+        "ends_with" => Function::new("ends_with", 2, Some(2), |args| {
+            Ok(Value::Bool(string("ends_with", &args[0])?.ends_with(string("ends_with", &args[1])?)))
+        }),
+        "split" => Function::new("split", 2, Some(2), |args| {
+            let s = string("split", &args[0])?;
+            let sep = string("split", &args[1])?;
+            if sep.is_empty() {
+                return Err(SML_Error::BadOperation("split separator is empty.".to_string()));
+            }
+            Ok(Value::List(s.split(sep).map(|part| Value::String(part.to_string())).collect()))
+        }),
+        "slice" => Function::new("slice", 2, Some(3), |args| {
+            let start = integer("slice", &args[1])?;
+            let end = args.get(2).map(|end| integer("slice", end)).transpose()?;
+            match &args[0] {
+                Value::String(s) => {
+                    let range = slice_range(start, end, s.chars().count());
+                    Ok(Value::String(s.chars().skip(range.start).take(range.len()).collect()))
+                },
+                Value::List(items) => Ok(Value::List(items[slice_range(start, end, items.len())].to_vec())),
+                v => Err(SML_Error::BadOperation(format!("slice expects a string or list, got {v}."))),
+            }
+        }),
+        "str" => Function::new("str", 1, Some(1), |args| Ok(Value::String(args[0].as_string()))),
+        "num" => Function::new("num", 1, Some(1), |args| match &args[0] {
+            Value::Number(n) => Ok(Value::Number(*n)),
+            Value::String(s) => s.trim().parse()
+                .map(Value::Number)
+                .map_err(|_| SML_Error::BadOperation(format!("num could not convert \"{s}\" to a number."))),
+            v => Err(SML_Error::BadOperation(format!("num expects a string, got {v}."))),
+        }),
+
+        // Lists. None of these change the list they are given: assign the result back to do that.
+        "concat" => Function::new("concat", 1, None, |args| {
+            let mut joined = Vec::new();
+            for arg in args {
+                joined.extend_from_slice(list("concat", arg)?);
+            }
+            Ok(Value::List(joined))
+        }),
+        "insert" => Function::new("insert", 3, Some(3), |args| {
+            let mut items = list("insert", &args[0])?.to_vec();
+            let index = integer("insert", &args[1])?;
+            // Inserting at the length of the list appends.
+            let i = resolve_index(index, items.len() + 1)
+                .ok_or_else(|| SML_Error::BadOperation(format!("insert index {index} out of range for list of length {}.", items.len())))?;
+            items.insert(i, args[2].clone());
+            Ok(Value::List(items))
+        }),
+        "remove" => Function::new("remove", 2, Some(2), |args| {
+            let mut items = list("remove", &args[0])?.to_vec();
+            let index = integer("remove", &args[1])?;
+            let i = resolve_index(index, items.len())
+                .ok_or_else(|| SML_Error::BadOperation(format!("remove index {index} out of range for list of length {}.", items.len())))?;
+            items.remove(i);
+            Ok(Value::List(items))
+        }),
+        "pop" => list_op("pop", |items| match items {
+            [] => Err(SML_Error::BadOperation("pop from an empty list.".to_string())),
+            [rest @ .., _] => Ok(rest.to_vec()),
+        }),
+        "sort" => list_op("sort", sorted),
+        "reverse" => list_op("reverse", |items| Ok(items.iter().rev().cloned().collect())),
+        "unique" => list_op("unique", |items| {
+            let mut unique: Vec<Value> = Vec::new();
+            for item in items {
+                if !unique.contains(item) {
+                    unique.push(item.clone());
+                }
+            }
+            Ok(unique)
+        }),
+        "sum" => Function::new("sum", 1, Some(1), |args| {
+            let items = list("sum", &args[0])?;
+            let total = items.iter().map(|v| number("sum", v)).sum::<SML_Result<f64>>()?;
+            Ok(Value::Number(total))
+        }),
+        "mean" => Function::new("mean", 1, Some(1), |args| {
+            let items = numbers("mean", &args[..1])?;
+            Ok(Value::Number(items.iter().sum::<f64>() / items.len() as f64))
+        }),
+
+        _ => { return None; },
+    };
+    Some(f)
+}
+
+
+/// Native functions to make available to SML, by name.
+///
+/// Functions are registered on the registry, which is then given to
+/// [compile_with_registry](crate::compile_with_registry). Calls are checked when the machine is
+/// compiled, so an unknown name is a compile error. A registered function shadows a built-in
+/// function of the same name.
+/// ```
+/// use shakemyleg::{compile_with_registry, Registry, Value, SML_Error};
+///
+/// let registry = Registry::new()
+///     .register_with_arity("setpoint", 1, |args: &[Value]| match &args[0] {
+///         Value::String(zone) if zone == "oven" => Ok(Value::Number(180.0)),
+///         zone => Err(SML_Error::BadOperation(format!("no setpoint for {zone}"))),
+///     });
+///
+/// let src = r#"
+/// state A:
+///   always:
+///     outputs.target = setpoint("oven")
+/// "#;
+/// let mut sm = compile_with_registry(src, &registry).unwrap();
+/// let o: serde_json::Value = sm.run(serde_json::json!({})).unwrap().unwrap();
+/// assert_eq!(o["target"], 180);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Registry {
+    functions: HashMap<String, Function>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a function taking any number of arguments.
+    pub fn register<F>(self, name: &str, f: F) -> Self
+    where
+        F: Fn(&[Value]) -> SML_Result<Value> + Send + Sync + 'static
+    {
+        self.insert(Function::new(name, 0, None, f))
+    }
+
+    /// Register a function taking exactly `n_args` arguments. Calls with any other number of
+    /// arguments are compile errors.
+    pub fn register_with_arity<F>(self, name: &str, n_args: usize, f: F) -> Self
+    where
+        F: Fn(&[Value]) -> SML_Result<Value> + Send + Sync + 'static
+    {
+        self.insert(Function::new(name, n_args, Some(n_args), f))
+    }
+
+    fn insert(mut self, function: Function) -> Self {
+        let function = Function { native: true, ..function };
+        self.functions.insert(function.name.clone(), function);
+        self
+    }
+
+    /// Look up a function by name: registered functions first, then built-ins.
+    pub fn get(&self, name: &str) -> Option<Function> {
+        self.functions.get(name).cloned().or_else(|| builtin(name))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(name: &str, args: &[f64]) -> SML_Result<Value> {
+        let args: Vec<_> = args.iter().map(|n| Value::Number(*n)).collect();
+        let f = builtin(name).unwrap();
+        f.check_arity(args.len()).map_err(SML_Error::BadOperation)?;
+        f.call(&args)
+    }
+
+    #[test]
+    fn test_builtins() {
+        assert_eq!(call("abs", &[-2.0]).unwrap(), Value::Number(2.0));
+        assert_eq!(call("round", &[2.5]).unwrap(), Value::Number(3.0));
+        assert_eq!(call("min", &[3.0, 1.0, 2.0]).unwrap(), Value::Number(1.0));
+        assert_eq!(call("max", &[3.0, 1.0, 2.0]).unwrap(), Value::Number(3.0));
+        assert_eq!(call("clamp", &[5.0, 0.0, 1.0]).unwrap(), Value::Number(1.0));
+        assert!(call("clamp", &[5.0, 1.0, 0.0]).is_err());
+        assert_eq!(call("approx", &[0.1 + 0.2, 0.3]).unwrap(), Value::Bool(true));
+        assert_eq!(call("approx", &[1.0, 1.01, 0.05]).unwrap(), Value::Bool(true));
+        assert_eq!(call("approx", &[1.0, 1.1, 0.05]).unwrap(), Value::Bool(false));
+        assert!(call("approx", &[1.0, 1.0, -1.0]).is_err());
+    }
+
+    fn call_str(name: &str, args: &[Value]) -> Value {
+        builtin(name).unwrap().call(args).unwrap()
+    }
+
+    #[test]
+    fn test_string_builtins() {
+        let s = |s: &str| Value::String(s.to_string());
+        let n = |n: f64| Value::Number(n);
+        assert_eq!(call_str("len", &[s("héllo")]), n(5.0));
+        assert_eq!(call_str("upper", &[s("abc")]), s("ABC"));
+        assert_eq!(call_str("trim", &[s("  a b ")]), s("a b"));
+        assert_eq!(call_str("starts_with", &[s("abc"), s("ab")]), Value::Bool(true));
+        assert_eq!(call_str("split", &[s("a,b"), s(",")]), Value::List(vec![s("a"), s("b")]));
+        assert_eq!(call_str("slice", &[s("héllo"), n(1.0), n(3.0)]), s("él"));
+        assert_eq!(call_str("slice", &[s("hello"), n(-3.0)]), s("llo"));
+        assert_eq!(call_str("slice", &[s("hello"), n(3.0), n(99.0)]), s("lo"));
+        assert_eq!(call_str("str", &[n(2.5)]), s("2.5"));
+        assert_eq!(call_str("num", &[s(" 2.5")]), n(2.5));
+        assert!(builtin("num").unwrap().call(&[s("x")]).is_err());
+    }
+
+    #[test]
+    fn test_list_builtins() {
+        let n = |n: f64| Value::Number(n);
+        let l = |items: &[f64]| Value::List(items.iter().map(|i| n(*i)).collect());
+        assert_eq!(call_str("len", &[l(&[1.0, 2.0])]), n(2.0));
+        assert_eq!(call_str("slice", &[l(&[1.0, 2.0, 3.0]), n(-2.0)]), l(&[2.0, 3.0]));
+        assert_eq!(call_str("concat", &[l(&[1.0]), l(&[]), l(&[2.0, 3.0])]), l(&[1.0, 2.0, 3.0]));
+        assert_eq!(call_str("insert", &[l(&[1.0, 3.0]), n(1.0), n(2.0)]), l(&[1.0, 2.0, 3.0]));
+        assert_eq!(call_str("insert", &[l(&[1.0]), n(1.0), n(2.0)]), l(&[1.0, 2.0]));
+        assert_eq!(call_str("remove", &[l(&[1.0, 2.0, 3.0]), n(0.0)]), l(&[2.0, 3.0]));
+        assert_eq!(call_str("pop", &[l(&[1.0, 2.0])]), l(&[1.0]));
+        assert_eq!(call_str("sort", &[l(&[3.0, 1.0, 2.0])]), l(&[1.0, 2.0, 3.0]));
+        assert_eq!(call_str("reverse", &[l(&[1.0, 2.0])]), l(&[2.0, 1.0]));
+        assert_eq!(call_str("unique", &[l(&[2.0, 1.0, 2.0])]), l(&[2.0, 1.0]));
+        assert_eq!(call_str("sum", &[l(&[1.0, 2.0])]), n(3.0));
+        assert_eq!(call_str("sum", &[l(&[])]), n(0.0));
+        assert_eq!(call_str("mean", &[l(&[1.0, 2.0])]), n(1.5));
+
+        assert!(builtin("pop").unwrap().call(&[l(&[])]).is_err());
+        assert!(builtin("mean").unwrap().call(&[l(&[])]).is_err());
+        assert!(builtin("remove").unwrap().call(&[l(&[1.0]), n(1.0)]).is_err());
+        let mixed = Value::List(vec![n(1.0), Value::String("a".to_string())]);
+        assert!(builtin("sort").unwrap().call(&[mixed]).is_err());
+    }
+
+    #[test]
+    fn test_builtin_min_of_list() {
+        let list = Value::List(vec![Value::Number(2.0), Value::Number(-1.0)]);
+        assert_eq!(builtin("min").unwrap().call(&[list]).unwrap(), Value::Number(-1.0));
+        assert!(builtin("min").unwrap().call(&[Value::List(vec![])]).is_err());
+    }
+
+    #[test]
+    fn test_registry() {
+        let registry = Registry::new()
+            .register("abs", |_| Ok(Value::Null))
+            .register_with_arity("double", 1, |args| Ok(Value::Number(number("double", &args[0])? * 2.0)));
+
+        let abs = registry.get("abs").unwrap();
+        assert!(abs.is_native());
+        assert_eq!(abs.call(&[]).unwrap(), Value::Null);
+
+        let double = registry.get("double").unwrap();
+        assert!(double.check_arity(2).is_err());
+        assert_eq!(double.call(&[Value::Number(2.0)]).unwrap(), Value::Number(4.0));
+
+        assert!(!registry.get("sqrt").unwrap().is_native());
+        assert!(registry.get("frobnicate").is_none());
+    }
+
+    #[test]
+    fn test_arity() {
+        assert!(call("abs", &[]).is_err());
+        assert!(call("abs", &[1.0, 2.0]).is_err());
+        assert!(call("min", &[]).is_err());
+        assert_eq!(builtin("atan2").unwrap().check_arity(1), Err("atan2 takes 2 arguments, got 1.".to_string()));
+        assert_eq!(builtin("min").unwrap().check_arity(0), Err("min takes at least 1 argument, got 0.".to_string()));
+    }
+}
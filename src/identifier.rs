@@ -1,59 +1,107 @@
+use std::fmt;
+
 use json::JsonValue;
-use itertools::{Itertools, Position};
 
 use crate::error::{SML_Error, SML_Result};
-use crate::value::Value;
+use crate::expression::Expression;
+use crate::value::{resolve_index, Value};
 
 
 #[derive(Clone, Debug)]
 pub enum IdentifierStore {
     Inputs,
     Outputs,
-    Globals
+    Globals,
+
+    /// Scratch values, which last for one run of the machine and are never output.
+    Locals,
+}
+
+/// One step along the path of an identifier: `.name`, or `[expression]`.
+#[derive(Clone, Debug)]
+pub enum PathSegment {
+    Field(String),
+
+    /// A list index, or a key computed at runtime. Negative indices count from the end of a list.
+    Index(Box<Expression>),
+}
+
+/// A path segment, with any index expression evaluated.
+enum Key {
+    Field(String),
+    Index(i64),
 }
 
 #[derive(Clone, Debug)]
 pub struct Identifier {
     store: IdentifierStore,
-    name: String,
-    path: Vec<String>,
+    path: Vec<PathSegment>,
 }
 
 impl Identifier {
-    pub fn from_str(s: String) -> SML_Result<Self> {
-        let parts: Vec<_> = s.split(".").collect();
-        if parts.len() < 2 {
-            return Err(SML_Error::SyntaxError(format!("Identifier must specify store location i.e., start with \"inputs.\", \"globals.\", or \"outputs.\". (Note full-stops.) Got {s:?}")));
+    pub fn new(store: &str, path: Vec<PathSegment>) -> SML_Result<Self> {
+        if path.is_empty() {
+            return Err(SML_Error::IdentifierError(format!("Identifier must name a value in the store, not just the store itself. Got {store:?}")));
         }
 
-        let store = match parts[0] {
+        let store = match store {
             "inputs" => IdentifierStore::Inputs,
             "outputs" => IdentifierStore::Outputs,
             "globals" => IdentifierStore::Globals,
+            "locals" => IdentifierStore::Locals,
             _ => {
-                return Err(SML_Error::SyntaxError(format!("Identifier must specify store location i.e., start with \"inputs.\", \"globals.\", or \"outputs.\". Got: {:?}", parts[0])));
+                return Err(SML_Error::IdentifierError(format!("Identifier must specify store location i.e., start with \"inputs.\", \"globals.\", \"outputs.\", or \"locals.\". Got: {store:?}")));
             }
         };
 
-        let path: Vec<_> = parts[1..].iter().map(|s| { s.to_string() } ).collect();
-        let name = path.join(".");
+        Ok(Self { store, path })
+    }
+
+    pub fn from_str(s: String) -> SML_Result<Self> {
+        let parts: Vec<_> = s.split(".").collect();
+        if parts.len() < 2 {
+            return Err(SML_Error::IdentifierError(format!("Identifier must specify store location i.e., start with \"inputs.\", \"globals.\", \"outputs.\", or \"locals.\". (Note full-stops.) Got {s:?}")));
+        }
 
-        Ok(Self { name, path, store })
+        let path = parts[1..].iter().map(|s| PathSegment::Field(s.to_string())).collect();
+        Self::new(parts[0], path)
     }
 
-    pub fn get(&self, i: &JsonValue, o: &JsonValue, g: &JsonValue) -> SML_Result<Value> {
-        let mut store = match self.store {
+    /// Evaluate index expressions along the path.
+    fn keys(&self, i: &JsonValue, o: &mut JsonValue, g: &mut JsonValue, l: &mut JsonValue) -> SML_Result<Vec<Key>> {
+        self.path.iter()
+            .map(|segment| match segment {
+                PathSegment::Field(name) => Ok(Key::Field(name.clone())),
+                PathSegment::Index(expression) => match expression.evaluate(i, o, g, l)? {
+                    Value::Number(n) if n.fract() == 0.0 => Ok(Key::Index(n as i64)),
+                    Value::String(s) => Ok(Key::Field(s)),
+                    v => Err(SML_Error::IdentifierError(format!("Index must be a whole number (for lists) or a string (for keys). Got {v} in \"{self}\"."))),
+                },
+            })
+            .collect()
+    }
+
+    /// Position of `index` in a list of length `len`, counting from the end if negative.
+    fn list_index(&self, index: i64, len: usize) -> SML_Result<usize> {
+        resolve_index(index, len)
+            .ok_or_else(|| SML_Error::IdentifierError(format!("Index {index} out of range for list of length {len} in \"{self}\".")))
+    }
+
+    pub fn get(&self, i: &JsonValue, o: &mut JsonValue, g: &mut JsonValue, l: &mut JsonValue) -> SML_Result<Value> {
+        let keys = self.keys(i, o, g, l)?;
+        let mut store: &JsonValue = match self.store {
             IdentifierStore::Inputs => i,
             IdentifierStore::Outputs => o,
             IdentifierStore::Globals => g,
+            IdentifierStore::Locals => l,
         };
 
-        for node in &self.path {
-            if !store.is_object() || !store.has_key(node) {
-                return Err(SML_Error::IdentifierNameError(self.name.clone()));
-            }
-
-            store = &store[node];
+        for key in keys {
+            store = match key {
+                Key::Field(name) if store.is_object() && store.has_key(&name) => &store[name.as_str()],
+                Key::Index(index) if store.is_array() => &store[self.list_index(index, store.len())?],
+                _ => { return Err(SML_Error::IdentifierNameError(self.to_string())); },
+            };
         }
 
         let value = Value::new(store)?;
@@ -61,49 +109,76 @@ impl Identifier {
         Ok(value)
     }
 
-    pub fn set(&self, o: &mut JsonValue, g: &mut JsonValue, v: &Value) -> SML_Result<()> {
+    pub fn set(&self, i: &JsonValue, o: &mut JsonValue, g: &mut JsonValue, l: &mut JsonValue, v: &Value) -> SML_Result<()> {
+        let keys = self.keys(i, o, g, l)?;
         let mut store = match self.store {
             IdentifierStore::Inputs => { return Err(SML_Error::InputsWriteError); },
             IdentifierStore::Outputs => o,
             IdentifierStore::Globals => g,
+            IdentifierStore::Locals => l,
         };
 
-        let mut key = None;
-        for (pos, node) in self.path.iter().with_position() {
-            if let Position::Last | Position::Only = pos {
-                key = Some(node);
-                break;
-            }
-            if !store.is_object() {
-                if store.has_key(node) {
-                    return Err(SML_Error::IdentifierError(format!("Cannot set sub-value of non-object. Identifier \"{}\" collides with another variable.", self.name)));
-                }
-                else {
-                    store[node] = json::object! { };
+        // Objects along the path are created as needed; lists must already exist.
+        for key in keys {
+            if store.is_null() {
+                if let Key::Field(_) = key {
+                    *store = json::object! { };
                 }
             }
 
-            store = &mut store[node];
+            store = match key {
+                Key::Field(name) if store.is_object() => &mut store[name.as_str()],
+                Key::Index(index) if store.is_array() => {
+                    let index = self.list_index(index, store.len())?;
+                    &mut store[index]
+                },
+                Key::Field(_) => {
+                    return Err(SML_Error::IdentifierError(format!("Cannot set sub-value of non-object. Identifier \"{self}\" collides with another variable.")));
+                },
+                Key::Index(_) => {
+                    return Err(SML_Error::IdentifierError(format!("Cannot index into non-list in \"{self}\".")));
+                },
+            };
         }
 
-        let json_value = v.as_json();
-        store[key.unwrap()] = json_value;
+        *store = v.as_json();
 
         Ok(())
     }
 }
 
+impl fmt::Display for Identifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let store = match self.store {
+            IdentifierStore::Inputs => "inputs",
+            IdentifierStore::Outputs => "outputs",
+            IdentifierStore::Globals => "globals",
+            IdentifierStore::Locals => "locals",
+        };
+        write!(f, "{store}")?;
+        for segment in &self.path {
+            match segment {
+                PathSegment::Field(name) => write!(f, ".{name}")?,
+                PathSegment::Index(expression) => write!(f, "[{expression}]")?,
+            }
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_store_set() {
+        let i = json::object! { };
         let mut g = json::object! { };
+        let mut l = json::object! { };
         let mut o = json::object! { };
         let ident = Identifier::from_str("outputs.foo.bar".to_string()).unwrap();
         let v = Value::Number(1.0);
-        ident.set(&mut o, &mut g, &v).unwrap();
+        ident.set(&i, &mut o, &mut g, &mut l, &v).unwrap();
 
         assert!(g.is_empty());
         assert!(!o.is_empty());
@@ -111,4 +186,27 @@ mod tests {
         assert!(o["foo"].has_key("bar"));
         assert!(matches!(o["foo"]["bar"], JsonValue::Number(_)));
     }
+
+    #[test]
+    fn test_store_index() {
+        let index = |n: f64| PathSegment::Index(Box::new(Expression::Value(Value::Number(n))));
+        let field = |s: &str| PathSegment::Field(s.to_string());
+        let i = json::object! { "readings": [1, 2, 3] };
+        let mut g = json::object! { "buf": [[0], [0]] };
+        let mut l = json::object! { };
+        let mut o = json::object! { };
+
+        let last = Identifier::new("inputs", vec![field("readings"), index(-1.0)]).unwrap();
+        assert_eq!(last.get(&i, &mut o, &mut g, &mut l).unwrap(), Value::Number(3.0));
+
+        let out_of_range = Identifier::new("inputs", vec![field("readings"), index(3.0)]).unwrap();
+        assert!(out_of_range.get(&i, &mut o, &mut g, &mut l).is_err());
+
+        let nested = Identifier::new("globals", vec![field("buf"), index(1.0), index(0.0)]).unwrap();
+        nested.set(&i, &mut o, &mut g, &mut l, &Value::Number(5.0)).unwrap();
+        assert_eq!(g["buf"][1][0], 5);
+
+        let not_list = Identifier::new("outputs", vec![field("x"), index(0.0)]).unwrap();
+        assert!(not_list.set(&i, &mut o, &mut g, &mut l, &Value::Number(5.0)).is_err());
+    }
 }
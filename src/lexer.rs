@@ -0,0 +1,572 @@
+use std::fmt;
+use std::ops::Range;
+
+use chumsky::prelude::*;
+use chumsky::error::SimpleReason;
+
+use crate::diagnostic::Diagnostic;
+
+
+pub type Span = Range<usize>;
+
+/// Lexed tokens, each with its span.
+pub type Tokens = Vec<(Token, Span)>;
+
+
+/// A lex or parse error, located by a byte range in the source.
+#[derive(Clone, Debug)]
+pub struct ParseError {
+    pub span: Span,
+    pub message: String,
+    pub hint: Option<String>,
+}
+
+impl ParseError {
+    pub fn new<M: ToString>(span: Span, message: M) -> Self {
+        Self { span, message: message.to_string(), hint: None }
+    }
+
+    pub fn with_hint<H: ToString>(mut self, hint: H) -> Self {
+        self.hint = Some(hint.to_string());
+        self
+    }
+
+    pub fn into_diagnostic(self, src: &str) -> Diagnostic {
+        let diagnostic = Diagnostic::error(src, self.span, self.message);
+        match self.hint {
+            Some(hint) => diagnostic.with_hint(hint),
+            None => diagnostic,
+        }
+    }
+
+    pub fn from_simple<T: fmt::Display + std::hash::Hash + Eq>(e: Simple<T>) -> Self {
+        let mut hint = None;
+        let message = match e.reason() {
+            SimpleReason::Custom(msg) => msg.clone(),
+            SimpleReason::Unclosed { delimiter, .. } => {
+                hint = Some("add the matching closing bracket".to_string());
+                format!("Unclosed delimiter '{delimiter}'")
+            },
+            SimpleReason::Unexpected => {
+                let found = match e.found() {
+                    Some(t) => format!("'{}'", t.to_string().escape_debug()),
+                    None => "end of input".to_string(),
+                };
+                let mut expected: Vec<_> = e.expected()
+                    .map(|t| match t {
+                        Some(t) => format!("'{t}'"),
+                        None => "end of input".to_string(),
+                    })
+                    .collect();
+                // Listing every operator that could continue an expression is just noise if
+                // something more structural (like a colon) was also expected.
+                if expected.iter().any(|t| !OPERATORS.iter().any(|op| *t == format!("'{op}'"))) {
+                    expected.retain(|t| !OPERATORS.iter().any(|op| *t == format!("'{op}'")));
+                }
+                expected.sort();
+                if e.label() == Some("closing quote") {
+                    hint = Some("strings must be closed on the line they start on".to_string());
+                }
+                else if expected.iter().any(|t| t == "':'") {
+                    hint = Some("headers (`state`, `when`, `always`, ...) end with a colon".to_string());
+                }
+                match (e.label(), expected.len()) {
+                    (Some(label), _) => format!("Unexpected {found}, expected {label}"),
+                    (None, 0) => format!("Unexpected {found}"),
+                    (None, _) => format!("Unexpected {found}, expected {}", expected.join(" or ")),
+                }
+            },
+        };
+        Self { span: e.span(), message, hint }
+    }
+}
+
+
+/// Tokens of SML source. Words (keywords, store names, state names) are all lexed as `Ident` and
+/// told apart by the parser. Indentation is turned into `Indent`/`Dedent` pairs, and each logical
+/// line is terminated by a `Newline`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Token {
+    Ident(String),
+    Num(String),
+    Str(String),
+
+    /// Contents of an f-string, `f"..."`. Expressions in braces are parsed by the parser.
+    FStr(String),
+    Op(&'static str),
+    Ctrl(char),
+    Newline,
+    Indent,
+    Dedent,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ident(s) => write!(f, "{s}"),
+            Self::Num(s) => write!(f, "{s}"),
+            Self::Str(s) => write!(f, "{s:?}"),
+            Self::FStr(s) => write!(f, "f{s:?}"),
+            Self::Op(s) => write!(f, "{s}"),
+            Self::Ctrl(c) => write!(f, "{c}"),
+            Self::Newline => write!(f, "end of line"),
+            Self::Indent => write!(f, "indent"),
+            Self::Dedent => write!(f, "dedent"),
+        }
+    }
+}
+
+
+/// A `#` comment, which runs to the end of the line. Comments don't affect compilation, but are
+/// kept for the formatter.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Comment {
+    /// Text of the comment, including the `#`.
+    pub text: String,
+    pub span: Span,
+}
+
+
+/// Operators, longest first so that e.g. `<=` is not lexed as `<` then `=`.
+const OPERATORS: [&str; 32] = [
+    "//=",
+    "==", "!=", "~=", "<=", ">=", "^=", "&&", "||", "//", "<<", ">>",
+    "+=", "-=", "*=", "/=", "%=", "++", "--",
+    "=", "<", ">", "+", "-", "*", "/", "^", "%", "&", "|", "?", "!",
+];
+
+
+/// Raw output of the character-level lexer: a token, a line break followed by the indentation
+/// of the next line, or a comment.
+#[derive(Clone, Debug)]
+enum RawToken {
+    Token(Token),
+    LineStart(usize),
+    Comment(String),
+}
+
+/// Width of a run of indentation. Tabs advance to the next multiple of `TAB_WIDTH` so that
+/// tab-indented and space-indented files lex the same.
+const TAB_WIDTH: usize = 4;
+
+fn indent_width(ws: &str) -> usize {
+    ws.chars().fold(0, |w, c| {
+        if c == '\t' { (w / TAB_WIDTH + 1) * TAB_WIDTH } else { w + 1 }
+    })
+}
+
+/// Value of a number literal: decimal, optionally with a fraction and exponent (`1.5e-3`), hex
+/// (`0x1F`) or binary (`0b1010`). Digits may be separated by underscores (`1_000`).
+pub(crate) fn parse_number(s: &str) -> Result<f64, String> {
+    let malformed = || format!("Malformed number '{s}'");
+
+    let (digits, radix) = match s.get(..2) {
+        Some("0x" | "0X") => (&s[2..], 16),
+        Some("0b" | "0B") => (&s[2..], 2),
+        _ => (s, 10),
+    };
+
+    // Underscores may only separate digits.
+    let chars: Vec<char> = digits.chars().collect();
+    let separates_digits = |i: usize| {
+        i > 0 && i + 1 < chars.len() && chars[i - 1].is_digit(radix) && chars[i + 1].is_digit(radix)
+    };
+    if (0..chars.len()).any(|i| chars[i] == '_' && !separates_digits(i)) {
+        return Err(malformed());
+    }
+    let digits = digits.replace('_', "");
+
+    if radix == 10 {
+        digits.parse().map_err(|_| malformed())
+    }
+    else {
+        u64::from_str_radix(&digits, radix)
+            .map(|n| n as f64)
+            .map_err(|_| malformed())
+    }
+}
+
+/// Read an escape sequence from `chars`, just after its backslash: `\"`, `\\`, `\n`, `\r`, `\t`,
+/// `\0` or `\u{..}`.
+pub(crate) fn read_escape(chars: &mut impl Iterator<Item = char>) -> Result<char, String> {
+    match chars.next() {
+        Some('"') => Ok('"'),
+        Some('\\') => Ok('\\'),
+        Some('n') => Ok('\n'),
+        Some('r') => Ok('\r'),
+        Some('t') => Ok('\t'),
+        Some('0') => Ok('\0'),
+        Some('u') => {
+            let bad = || "Malformed unicode escape; write it as '\\u{1F600}'".to_string();
+            if chars.next() != Some('{') {
+                return Err(bad());
+            }
+            let hex: String = chars.by_ref().take_while(|c| *c != '}').collect();
+            u32::from_str_radix(&hex, 16).ok()
+                .and_then(char::from_u32)
+                .ok_or_else(bad)
+        },
+        Some(c) => Err(format!("Unknown escape sequence '\\{c}'")),
+        None => Err("Unfinished escape sequence".to_string()),
+    }
+}
+
+/// Contents of a string literal, with escape sequences replaced.
+pub(crate) fn unescape(s: &str) -> Result<String, String> {
+    let mut chars = s.chars();
+    let mut rv = String::new();
+    while let Some(c) = chars.next() {
+        rv.push(if c == '\\' { read_escape(&mut chars)? } else { c });
+    }
+    Ok(rv)
+}
+
+/// A string written as a literal, escaping quotes, backslashes and control characters.
+pub(crate) fn escape(s: &str) -> String {
+    let mut rv = String::new();
+    for c in s.chars() {
+        match c {
+            '"' => rv.push_str("\\\""),
+            '\\' => rv.push_str("\\\\"),
+            '\n' => rv.push_str("\\n"),
+            '\r' => rv.push_str("\\r"),
+            '\t' => rv.push_str("\\t"),
+            '\0' => rv.push_str("\\0"),
+            c if c.is_control() => rv.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => rv.push(c),
+        }
+    }
+    rv
+}
+
+fn raw_lexer() -> impl Parser<char, Vec<(RawToken, Span)>, Error = Simple<char>> {
+    let inline_ws = one_of(" \t").repeated();
+
+    let indentation = one_of(" \t")
+        .repeated()
+        .collect::<String>()
+        .map(|ws| RawToken::LineStart(indent_width(&ws)));
+
+    let line_start = just('\r').or_not()
+        .ignore_then(just('\n'))
+        .ignore_then(indentation.clone());
+
+    let comment = just('#')
+        .chain(none_of("\r\n").repeated())
+        .collect::<String>()
+        .map(|s| RawToken::Comment(s.trim_end().to_string()));
+
+    // Numbers are lexed loosely, taking any letters and digits that follow, then checked; so `12ab`
+    // is one malformed number rather than a number then a word.
+    let digit = filter(char::is_ascii_digit);
+    let digits = filter(|c: &char| c.is_ascii_digit() || *c == '_').repeated();
+    let word_chars = filter(|c: &char| c.is_ascii_alphanumeric() || *c == '_').repeated();
+    let exponent = one_of("eE")
+        .chain::<char, _, _>(one_of("+-").or_not())
+        .chain::<char, _, _>(digit)
+        .chain::<char, _, _>(digits);
+    let fraction = just('.')
+        .chain::<char, _, _>(digit)
+        .chain::<char, _, _>(digits);
+    let decimal = digit
+        .chain::<char, _, _>(digits)
+        .chain::<char, _, _>(fraction.or_not().map(Option::unwrap_or_default))
+        .chain::<char, _, _>(exponent.or_not().map(Option::unwrap_or_default));
+    let prefixed = just('0')
+        .chain::<char, _, _>(one_of("xXbB"))
+        .chain::<char, _, _>(word_chars);
+    let num = prefixed
+        .or(decimal)
+        .chain::<char, _, _>(word_chars)
+        .collect::<String>()
+        .validate(|s, span, emit| {
+            if let Err(msg) = parse_number(&s) {
+                emit(Simple::custom(span, msg));
+            }
+            Token::Num(s)
+        });
+
+    // String contents are kept as written, so that escaped quotes don't end them; escapes are
+    // replaced after.
+    let str_body = just('"')
+        .ignore_then(just('\\').chain(none_of("\r\n"))
+            .or(none_of("\\\"\r\n").map(|c| vec![c]))
+            .repeated()
+            .flatten())
+        .then_ignore(just('"').labelled("closing quote"))
+        .collect::<String>();
+
+    // Escapes in f-strings are replaced by the parser, along with splitting out the expressions.
+    let fstr = just('f')
+        .ignore_then(str_body.clone())
+        .map(Token::FStr);
+
+    let str_ = str_body
+        .validate(|s, span, emit| match unescape(&s) {
+            Ok(s) => Token::Str(s),
+            Err(msg) => {
+                emit(Simple::custom(span, msg));
+                Token::Str(s)
+            },
+        });
+
+    let op = choice(OPERATORS.map(just))
+        .map(Token::Op);
+
+    let ctrl = one_of("()[]{},:.").map(Token::Ctrl);
+
+    let ident = text::ident().map(Token::Ident);
+
+    let token = num
+        .or(fstr)
+        .or(str_)
+        .or(op)
+        .or(ctrl)
+        .or(ident)
+        .map(RawToken::Token);
+
+    let item = token
+        .or(line_start)
+        .or(comment)
+        .map_with_span(|t, span| (t, span))
+        .padded_by(inline_ws)
+        .recover_with(skip_then_retry_until([]));
+
+    indentation
+        .map_with_span(|t, span| (t, span))
+        .then(item.repeated())
+        .map(|(first, mut rest)| {
+            rest.insert(0, first);
+            rest
+        })
+        .then_ignore(end())
+}
+
+
+/// Turn a stream of raw tokens into the final token stream, replacing line starts with
+/// `Newline`, `Indent` and `Dedent` tokens. Line breaks inside brackets are ignored, so an
+/// expression may be continued over several lines while a bracket is open. Comments are set
+/// aside.
+fn layout(raw: Vec<(RawToken, Span)>, eoi: Span) -> (Vec<(Token, Span)>, Vec<Comment>, Vec<ParseError>) {
+    let mut tokens: Vec<(Token, Span)> = Vec::new();
+    let mut comments = Vec::new();
+    let mut errors = Vec::new();
+    let mut indents = vec![0usize];
+    let mut brackets: Vec<(char, Span)> = Vec::new();
+    let mut pending = None;
+
+    for (raw, span) in raw {
+        let token = match raw {
+            RawToken::LineStart(width) => {
+                if brackets.is_empty() {
+                    pending = Some((width, span));
+                }
+                continue;
+            },
+            RawToken::Comment(text) => {
+                comments.push(Comment { text, span });
+                continue;
+            },
+            RawToken::Token(token) => token,
+        };
+
+        if let Some((width, ws_span)) = pending.take() {
+            if !tokens.is_empty() {
+                tokens.push((Token::Newline, ws_span.start..ws_span.start));
+            }
+
+            let here = ws_span.end..ws_span.end;
+            if width > *indents.last().unwrap() {
+                indents.push(width);
+                tokens.push((Token::Indent, here));
+            }
+            else {
+                while width < *indents.last().unwrap() {
+                    indents.pop();
+                    tokens.push((Token::Dedent, here.clone()));
+                }
+
+                if width != *indents.last().unwrap() {
+                    errors.push(ParseError::new(ws_span, "Inconsistent indentation: de-dent does not match any outer indentation level")
+                        .with_hint("indent by the same amount as the enclosing block, or one of its parents"));
+                    indents.push(width);
+                }
+            }
+        }
+
+        match token {
+            Token::Ctrl(c @ ('(' | '[' | '{')) => brackets.push((c, span.clone())),
+            Token::Ctrl(c @ (')' | ']' | '}')) => {
+                let open = match c { ')' => '(', ']' => '[', _ => '{' };
+                match brackets.pop() {
+                    Some((o, _)) if o == open => (),
+                    Some((o, o_span)) => {
+                        errors.push(ParseError::new(o_span, format!("Unclosed delimiter '{o}' (found '{c}')"))
+                            .with_hint("add the matching closing bracket"));
+                    },
+                    None => {
+                        errors.push(ParseError::new(span.clone(), format!("Unmatched closing bracket '{c}'"))
+                            .with_hint("remove it, or add the matching opening bracket"));
+                    },
+                }
+            },
+            _ => (),
+        }
+
+        tokens.push((token, span));
+    }
+
+    for (o, o_span) in brackets {
+        errors.push(ParseError::new(o_span, format!("Unclosed delimiter '{o}'"))
+            .with_hint("add the matching closing bracket"));
+    }
+
+    if !tokens.is_empty() {
+        tokens.push((Token::Newline, eoi.clone()));
+    }
+    for _ in 1..indents.len() {
+        tokens.push((Token::Dedent, eoi.clone()));
+    }
+
+    (tokens, comments, errors)
+}
+
+
+/// Lex SML source into tokens. Spans are byte ranges into `src`.
+pub fn lex(src: &str) -> Result<Tokens, Vec<ParseError>> {
+    lex_with_comments(src).map(|(tokens, _)| tokens)
+}
+
+/// Lex SML source into tokens, keeping comments too.
+pub fn lex_with_comments(src: &str) -> Result<(Tokens, Vec<Comment>), Vec<ParseError>> {
+    // chumsky spans count chars; map them back to byte offsets.
+    let offsets: Vec<usize> = src.char_indices().map(|(i, _)| i).chain([src.len()]).collect();
+    let to_bytes = |span: Span| offsets[span.start]..offsets[span.end];
+
+    let (raw, errors) = raw_lexer().parse_recovery(src);
+    let mut errors: Vec<_> = errors.into_iter()
+        .map(|e| {
+            let e = ParseError::from_simple(e);
+            ParseError { span: to_bytes(e.span.clone()), ..e }
+        })
+        .collect();
+    let raw = raw.unwrap_or_default()
+        .into_iter()
+        .map(|(t, span)| (t, to_bytes(span)))
+        .collect();
+
+    let (tokens, comments, layout_errors) = layout(raw, src.len()..src.len());
+    errors.extend(layout_errors);
+
+    if errors.is_empty() {
+        Ok((tokens, comments))
+    }
+    else {
+        Err(errors)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(src: &str) -> Vec<Token> {
+        lex(src).unwrap().into_iter().map(|(t, _)| t).collect()
+    }
+
+    #[test]
+    fn test_lex_layout() {
+        let tokens = kinds("state A:\n  always:\n    end\n");
+        let ident = |s: &str| Token::Ident(s.to_string());
+        assert_eq!(tokens, vec![
+            ident("state"), ident("A"), Token::Ctrl(':'), Token::Newline,
+            Token::Indent, ident("always"), Token::Ctrl(':'), Token::Newline,
+            Token::Indent, ident("end"), Token::Newline,
+            Token::Dedent, Token::Dedent,
+        ]);
+    }
+
+    #[test]
+    fn test_lex_tabs_and_spaces_agree() {
+        let tabs = kinds("state A:\n\talways:\n\t\tend\n");
+        let spaces = kinds("state A:\n    always:\n        end\n");
+        assert_eq!(tabs, spaces);
+    }
+
+    #[test]
+    fn test_lex_comments_and_blank_lines() {
+        let commented = kinds("# header\n\nstate A: # trailing\n    # own line\n    always:\n\n        end\n");
+        let plain = kinds("state A:\n    always:\n        end\n");
+        assert_eq!(commented, plain);
+    }
+
+    #[test]
+    fn test_lex_keeps_comments() {
+        let src = "# header\nstate A: # trailing  \n";
+        let (_, comments) = lex_with_comments(src).unwrap();
+        let texts: Vec<_> = comments.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(texts, ["# header", "# trailing"]);
+        assert_eq!(&src[comments[1].span.clone()], "# trailing  ");
+    }
+
+    #[test]
+    fn test_lex_fstr() {
+        let ident = |s: &str| Token::Ident(s.to_string());
+        assert_eq!(kinds("f\"n = {x}\" f"), vec![Token::FStr("n = {x}".to_string()), ident("f"), Token::Newline]);
+    }
+
+    #[test]
+    fn test_lex_numbers() {
+        for (src, n) in [("1.5", 1.5), ("1e-3", 1e-3), ("2.5E+2", 250.0), ("0x1F", 31.0), ("0b1010", 10.0), ("1_000_000", 1e6)] {
+            assert_eq!(kinds(src), vec![Token::Num(src.to_string()), Token::Newline]);
+            assert_eq!(parse_number(src), Ok(n));
+        }
+
+        // A hex literal doesn't take an exponent, and `.` needs a digit after it.
+        let ident = |s: &str| Token::Ident(s.to_string());
+        assert_eq!(kinds("0x1e-3"), vec![Token::Num("0x1e".to_string()), Token::Op("-"), Token::Num("3".to_string()), Token::Newline]);
+        assert_eq!(kinds("[0].x"), vec![Token::Ctrl('['), Token::Num("0".to_string()), Token::Ctrl(']'), Token::Ctrl('.'), ident("x"), Token::Newline]);
+
+        for src in ["1e", "12ab", "0x", "0b102", "1__0", "1_", "0x_1"] {
+            let errors = lex(src).unwrap_err();
+            assert_eq!(errors[0].message, format!("Malformed number '{src}'"));
+        }
+    }
+
+    #[test]
+    fn test_lex_escapes() {
+        let src = r#""a \"quote\" \\ \n\t \u{e9}""#;
+        assert_eq!(kinds(src)[0], Token::Str("a \"quote\" \\ \n\t \u{e9}".to_string()));
+
+        let errors = lex(r#""\q""#).unwrap_err();
+        assert_eq!(errors[0].message, "Unknown escape sequence '\\q'");
+        assert!(lex(r#""\u{110000}""#).is_err());
+
+        let s = "\"\\\n\u{7}é";
+        assert_eq!(unescape(&escape(s)).unwrap(), s);
+    }
+
+    #[test]
+    fn test_lex_brackets_join_lines() {
+        let tokens = kinds("(1 +\n    2)\n");
+        assert!(!tokens[..tokens.len() - 1].contains(&Token::Newline));
+        assert!(!tokens.contains(&Token::Indent));
+    }
+
+    #[test]
+    fn test_lex_byte_spans() {
+        let src = "\"é\" + 1";
+        let tokens = lex(src).unwrap();
+        let (_, span) = &tokens[1];
+        assert_eq!(&src[span.clone()], "+");
+    }
+
+    #[test]
+    fn test_lex_errors() {
+        assert!(lex("\"unterminated\n").is_err());
+        assert!(lex("(1 + 2\n").is_err());
+        assert!(lex("1 + 2)\n").is_err());
+        assert!(lex("a:\n    b\n  c\n").is_err());
+    }
+}
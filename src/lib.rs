@@ -1,16 +1,28 @@
 #![doc = include_str!("../README.md")]
 
+pub mod analysis;
 mod compiler;
+mod diagram;
+mod diagnostic;
 mod error;
+mod formatter;
+mod function;
 pub mod examples;
 mod value;
 mod identifier;
 mod operation;
 mod expression;
+mod lexer;
 mod parse_expression;
+mod ast;
+mod parser;
 mod state;
 mod state_machine;
 
 pub use crate::error::{SML_Error, SML_Result};
+pub use crate::diagnostic::{Diagnostic, Severity, SourceSpan, RuntimeLocation};
 pub use crate::state_machine::StateMachine;
-pub use crate::compiler::compile;
+pub use crate::compiler::{compile, compile_file, compile_with_diagnostics, compile_with_registry, compile_with_diagnostics_and_registry};
+pub use crate::function::Registry;
+pub use crate::value::Value;
+pub use crate::formatter::format;
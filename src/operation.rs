@@ -1,3 +1,11 @@
+//! Unary and binary operators.
+//!
+//! Equality (`==`, `!=`) is exact: numbers must be identical (use `~=` or `approx` to allow for
+//! rounding error), and lists and objects are equal if all their items are. Values of different
+//! types can't be compared, except that anything can be compared with `null`. Ordering (`<`, `<=`,
+//! `>`, `>=`) is only defined between two numbers, or two strings (which compare by code point).
+use std::fmt;
+
 use crate::error::{SML_Error, SML_Result};
 use crate::value::Value;
 
@@ -5,49 +13,81 @@ use crate::value::Value;
 #[derive(Clone, Debug)]
 pub enum UnaryOperation {
     // Arithmetic
+    Negate,
+
+    /// `x++`, which also stores the result back to `x`.
     Increment,
+
+    /// `x--`, which also stores the result back to `x`.
     Decrement,
 
     // Boolean
-    Negate,
+    Not,
 }
 
 
 impl UnaryOperation {
     pub fn apply(&self, operand: &Value) -> SML_Result<Value> {
         match self {
-            Self::Negate => {
-                match operand {
-                    Value::Bool(b) => Ok(Value::Bool(!b)),
-                    _ => Err(SML_Error::BadOperation("Negation only valid for boolean operands.".to_string()))
-                }
-            },
+            Self::Not => Ok(Value::Bool(!operand.as_bool())),
             _ => {
                 match operand {
                     Value::Number(v) => {
                         match self {
+                            Self::Negate => Ok(Value::Number(-v)),
                             Self::Decrement => Ok(Value::Number(*v - 1.0)),
                             Self::Increment => Ok(Value::Number(*v + 1.0)),
-                            Self::Negate => panic!(),
+                            Self::Not => panic!(),
                         }
                     },
-                    _ => Err(SML_Error::BadOperation("Incr/decrement only valid for numerical operands.".to_string()))
+                    Value::Bool(_) if matches!(self, Self::Negate) => {
+                        Err(SML_Error::BadOperation("'-' only valid for numerical operands; use '!' or 'not' to negate a boolean.".to_string()))
+                    },
+                    _ => Err(SML_Error::BadOperation(format!("'{self}' only valid for numerical operands.")))
                 }
             }
         }
     }
+
+    /// Whether the operator is written after its operand.
+    pub fn is_postfix(&self) -> bool {
+        matches!(self, Self::Increment | Self::Decrement)
+    }
+}
+
+impl fmt::Display for UnaryOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Increment => "++",
+            Self::Decrement => "--",
+            Self::Negate => "-",
+            Self::Not => "!",
+        };
+        write!(f, "{s}")
+    }
 }
 
+/// Binding strength of unary minus; see [BinaryOperation::precedence].
+pub const UNARY_PRECEDENCE: u8 = 11;
+
+/// Binding strength of `cond ? a : b`; see [BinaryOperation::precedence].
+pub const CONDITIONAL_PRECEDENCE: u8 = 1;
+
 #[derive(Clone, Debug)]
 pub enum BinaryOperation {
     Assign,
 
+    /// `x += y` and the like: `x = x + y`.
+    CompoundAssign(Box<BinaryOperation>),
+
     // Arithmetic
     Add,
     Subtract,
     Divide,
     Multiply,
     Power,
+    Modulo,
+    FloorDivide,
 
     // Comparison and equality
     LessThan,
@@ -56,19 +96,51 @@ pub enum BinaryOperation {
     GreaterThanOrEqual,
     Equal,
     NotEqual,
+    ApproxEqual,
 
     // Boolean
     And,
     Or,
 
+    // Bitwise, on whole numbers (or bools, for `&`, `|` and `xor`)
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
+
     // List ops
     Contains,
 }
 
+
+/// Relative tolerance of `~=`.
+const APPROX_TOLERANCE: f64 = 1e-9;
+
+/// Whether two numbers are equal but for rounding error: within [APPROX_TOLERANCE] of each other,
+/// relative to the larger (or absolutely, for numbers smaller than one).
+pub(crate) fn approx_equal(left: f64, right: f64) -> bool {
+    (left - right).abs() <= APPROX_TOLERANCE * left.abs().max(right.abs()).max(1.0)
+}
+
+/// A number as an integer, for bitwise operations.
+fn bits(op: &BinaryOperation, v: &Value) -> SML_Result<i64> {
+    match v {
+        Value::Number(n) if n.fract() == 0.0 => Ok(*n as i64),
+        v => Err(SML_Error::BadOperation(format!("'{op}' only valid for whole numbers, got {v}."))),
+    }
+}
+
 impl BinaryOperation {
     pub fn from_str(s: String) -> SML_Result<Self> {
         let rv = match s.as_str() {
             "=" => Self::Assign,
+            "+=" => Self::CompoundAssign(Box::new(Self::Add)),
+            "-=" => Self::CompoundAssign(Box::new(Self::Subtract)),
+            "*=" => Self::CompoundAssign(Box::new(Self::Multiply)),
+            "/=" => Self::CompoundAssign(Box::new(Self::Divide)),
+            "%=" => Self::CompoundAssign(Box::new(Self::Modulo)),
+            "//=" => Self::CompoundAssign(Box::new(Self::FloorDivide)),
 
             // Arithmetic
             "+" => Self::Add,
@@ -76,6 +148,8 @@ impl BinaryOperation {
             "*" => Self::Multiply,
             "/" => Self::Divide,
             "^" => Self::Power,
+            "%" => Self::Modulo,
+            "//" => Self::FloorDivide,
 
             // Comparison and equality
             "<" => Self::LessThan,
@@ -84,23 +158,56 @@ impl BinaryOperation {
             ">=" => Self::GreaterThanOrEqual,
             "==" => Self::Equal,
             "!=" => Self::NotEqual,
+            "~=" => Self::ApproxEqual,
 
             // Boolean
             "&&" => Self::And,
             "||" => Self::Or,
 
+            // Bitwise
+            "&" => Self::BitAnd,
+            "|" => Self::BitOr,
+            "xor" => Self::BitXor,
+            "<<" => Self::ShiftLeft,
+            ">>" => Self::ShiftRight,
+
             // List
             "^=" => Self::Contains,
 
-            s => { return Err(SML_Error::SyntaxError(format!("Invalid binary operation {s}"))); }
+            s => { return Err(SML_Error::BadOperation(format!("Invalid binary operation {s}"))); }
         };
 
         Ok(rv)
     }
 
+    /// How tightly the operator binds; higher binds tighter. Unary minus sits between
+    /// multiplication and power (see [UNARY_PRECEDENCE]), and `?:` between assignment and `||`.
+    ///
+    /// All operators are left-associative but assignment and power.
+    pub fn precedence(&self) -> u8 {
+        match self {
+            Self::Assign | Self::CompoundAssign(_) => 0,
+            Self::Or => 2,
+            Self::And => 3,
+            Self::LessThan | Self::LessThanOrEqual | Self::GreaterThan | Self::GreaterThanOrEqual
+                | Self::Equal | Self::NotEqual | Self::ApproxEqual | Self::Contains => 4,
+            Self::BitOr => 5,
+            Self::BitXor => 6,
+            Self::BitAnd => 7,
+            Self::ShiftLeft | Self::ShiftRight => 8,
+            Self::Add | Self::Subtract => 9,
+            Self::Multiply | Self::Divide | Self::Modulo | Self::FloorDivide => 10,
+            Self::Power => 12,
+        }
+    }
+
+    pub fn is_right_associative(&self) -> bool {
+        matches!(self, Self::Assign | Self::CompoundAssign(_) | Self::Power)
+    }
+
     pub fn apply(&self, left: &Value, right: &Value) -> SML_Result<Value> {
         match self {
-            Self::Assign => {
+            Self::Assign | Self::CompoundAssign(_) => {
                 panic!("assign handled elsewhere");
             },
 
@@ -108,13 +215,13 @@ impl BinaryOperation {
             Self::Add => {
                 match (left, right) {
                     (Value::Number(left), Value::Number(right)) => Ok(Value::Number(left + right)),
+                    (Value::String(left), Value::String(right)) => Ok(Value::String(format!("{left}{right}"))),
                     (Value::List(l), new_value) => {
                         let mut l = l.clone();
-                        let new_value = Box::new(new_value.clone());
-                        l.push(new_value);
+                        l.push(new_value.clone());
                         Ok(Value::List(l))
                     },
-                    _ => Err(SML_Error::BadOperation("'+' only valid for numerical operands or to add a value to a list.".to_string()))
+                    _ => Err(SML_Error::BadOperation("'+' only valid for numerical operands, to join two strings, or to add a value to a list.".to_string()))
                 }
             },
             Self::Subtract => {
@@ -141,48 +248,61 @@ impl BinaryOperation {
                     _ => Err(SML_Error::BadOperation("Arithmetic only valid for numerical operands.".to_string()))
                 }
             },
-
-            // Comparison
-            Self::LessThan => {
-                match (left, right) {
-                    (Value::Number(left), Value::Number(right)) => Ok(Value::Bool(left < right)),
-                    _ => Err(SML_Error::BadOperation("Comparison only valid for boolean operands.".to_string()))
-                }
-            },
-            Self::LessThanOrEqual => {
-                match (left, right) {
-                    (Value::Number(left), Value::Number(right)) => Ok(Value::Bool(left <= right)),
-                    _ => Err(SML_Error::BadOperation("Comparison only valid for boolean operands.".to_string()))
-                }
-            },
-            Self::GreaterThan => {
+            // `%` and `//` round towards negative infinity, so `x % n` has the sign of n (or is 0).
+            Self::Modulo | Self::FloorDivide => {
                 match (left, right) {
-                    (Value::Number(left), Value::Number(right)) => Ok(Value::Bool(left > right)),
-                    _ => Err(SML_Error::BadOperation("Comparison only valid for boolean operands.".to_string()))
-                }
-            },
-            Self::GreaterThanOrEqual => {
-                match (left, right) {
-                    (Value::Number(left), Value::Number(right)) => Ok(Value::Bool(left >= right)),
-                    _ => Err(SML_Error::BadOperation("Comparison only valid for boolean operands.".to_string()))
+                    (Value::Number(_), Value::Number(right)) if *right == 0.0 => {
+                        Err(SML_Error::BadOperation(format!("'{self}' by zero.")))
+                    },
+                    (Value::Number(left), Value::Number(right)) => {
+                        let quotient = (left / right).floor();
+                        match self {
+                            Self::Modulo => Ok(Value::Number(left - right * quotient)),
+                            _ => Ok(Value::Number(quotient)),
+                        }
+                    },
+                    _ => Err(SML_Error::BadOperation("Arithmetic only valid for numerical operands.".to_string()))
                 }
             },
 
-            // Equality
-            Self::Equal => {
-                match (left, right) {
-                    (Value::Number(left), Value::Number(right)) => Ok(Value::Bool( (left - right).abs() < 1e-5 )),
-                    (Value::Bool(left), Value::Bool(right)) => Ok(Value::Bool(*left && *right)),
-                    (Value::String(left), Value::String(right)) => Ok(Value::Bool(*left == *right)),
-                    _ => Err(SML_Error::BadOperation("Comparison only valid for boolean operands.".to_string()))
-                }
+            // Comparison and equality: see the module docs.
+            Self::LessThan | Self::LessThanOrEqual | Self::GreaterThan | Self::GreaterThanOrEqual => {
+                let ordering = match (left, right) {
+                    (Value::Number(left), Value::Number(right)) => left.partial_cmp(right),
+                    (Value::String(left), Value::String(right)) => Some(left.cmp(right)),
+                    _ => {
+                        return Err(SML_Error::BadOperation(format!(
+                            "'{self}' only valid between two numbers or two strings, got {} and {}.", left.type_name(), right.type_name()
+                        )));
+                    },
+                };
+                // NaN is unordered, so every comparison with it is false.
+                let rv = ordering.is_some_and(|ordering| match self {
+                    Self::LessThan => ordering.is_lt(),
+                    Self::LessThanOrEqual => ordering.is_le(),
+                    Self::GreaterThan => ordering.is_gt(),
+                    _ => ordering.is_ge(),
+                });
+                Ok(Value::Bool(rv))
+            },
+            Self::Equal | Self::NotEqual => {
+                let equal = match (left, right) {
+                    (Value::Null, _) | (_, Value::Null) => left == right,
+                    _ if left.type_name() == right.type_name() => left == right,
+                    _ => {
+                        return Err(SML_Error::BadOperation(format!(
+                            "Cannot compare {} with {} using '{self}'.", left.type_name(), right.type_name()
+                        )));
+                    },
+                };
+                Ok(Value::Bool(if matches!(self, Self::Equal) { equal } else { !equal }))
             },
-            Self::NotEqual => {
+            Self::ApproxEqual => {
                 match (left, right) {
-                    (Value::Number(left), Value::Number(right)) => Ok(Value::Bool( (left - right).abs() > 1e-5 )),
-                    (Value::Bool(left), Value::Bool(right)) => Ok(Value::Bool(!(*left && *right))),
-                    (Value::String(left), Value::String(right)) => Ok(Value::Bool(*left != *right)),
-                    _ => Err(SML_Error::BadOperation("Comparison only valid for boolean operands.".to_string()))
+                    (Value::Number(left), Value::Number(right)) => Ok(Value::Bool(approx_equal(*left, *right))),
+                    _ => Err(SML_Error::BadOperation(format!(
+                        "'~=' only valid between two numbers, got {} and {}.", left.type_name(), right.type_name()
+                    ))),
                 }
             },
 
@@ -198,6 +318,35 @@ impl BinaryOperation {
                 Ok(Value::Bool(left || right))
             },
             
+            // Bitwise ops
+            Self::BitAnd | Self::BitOr | Self::BitXor => {
+                if let (Value::Bool(left), Value::Bool(right)) = (left, right) {
+                    return match self {
+                        Self::BitAnd => Ok(Value::Bool(left & right)),
+                        Self::BitOr => Ok(Value::Bool(left | right)),
+                        _ => Ok(Value::Bool(left ^ right)),
+                    };
+                }
+                let (left, right) = (bits(self, left)?, bits(self, right)?);
+                let rv = match self {
+                    Self::BitAnd => left & right,
+                    Self::BitOr => left | right,
+                    _ => left ^ right,
+                };
+                Ok(Value::Number(rv as f64))
+            },
+            Self::ShiftLeft | Self::ShiftRight => {
+                let (left, right) = (bits(self, left)?, bits(self, right)?);
+                if !(0..64).contains(&right) {
+                    return Err(SML_Error::BadOperation(format!("Shift by {right} out of range; must be from 0 to 63.")));
+                }
+                let rv = match self {
+                    Self::ShiftLeft => left << right,
+                    _ => left >> right,
+                };
+                Ok(Value::Number(rv as f64))
+            },
+
             // List ops
             Self::Contains => {
                 match (left, right) {
@@ -205,7 +354,7 @@ impl BinaryOperation {
                         let rv = {
                             let mut rv = false;
                             for item in left.iter() {
-                                if **item == *value {
+                                if *item == *value {
                                     rv = true;
                                     break;
                                 }
@@ -213,14 +362,47 @@ impl BinaryOperation {
                             rv
                         };
                         Ok(Value::Bool(rv))
-                    }
-                    _ => Err(SML_Error::BadOperation("Invalid type. Syntax is '<list> contains <value>'.".to_string()))
+                    },
+                    (Value::String(left), Value::String(right)) => Ok(Value::Bool(left.contains(right.as_str()))),
+                    _ => Err(SML_Error::BadOperation("Invalid type. Syntax is '<list> ^= <value>' or '<string> ^= <substring>'.".to_string()))
                 }
             },
         }
     }
 }
 
+impl fmt::Display for BinaryOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Assign => "=",
+            Self::CompoundAssign(op) => { return write!(f, "{op}="); },
+            Self::Add => "+",
+            Self::Subtract => "-",
+            Self::Multiply => "*",
+            Self::Divide => "/",
+            Self::Power => "^",
+            Self::Modulo => "%",
+            Self::FloorDivide => "//",
+            Self::LessThan => "<",
+            Self::LessThanOrEqual => "<=",
+            Self::GreaterThan => ">",
+            Self::GreaterThanOrEqual => ">=",
+            Self::Equal => "==",
+            Self::NotEqual => "!=",
+            Self::ApproxEqual => "~=",
+            Self::And => "&&",
+            Self::Or => "||",
+            Self::BitAnd => "&",
+            Self::BitOr => "|",
+            Self::BitXor => "xor",
+            Self::ShiftLeft => "<<",
+            Self::ShiftRight => ">>",
+            Self::Contains => "^=",
+        };
+        write!(f, "{s}")
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -245,4 +427,68 @@ mod tests {
         assert!(matches!(result2, Value::Bool(true)));
     }
 
+    #[test]
+    fn test_equality() {
+        let eq = |left: Value, right: Value| BinaryOperation::Equal.apply(&left, &right);
+        let (n, s, b) = (Value::Number, |s: &str| Value::String(s.to_string()), Value::Bool);
+
+        assert_eq!(eq(b(false), b(false)).unwrap(), b(true));
+        assert_eq!(BinaryOperation::NotEqual.apply(&b(false), &b(false)).unwrap(), b(false));
+        assert_eq!(eq(n(0.1 + 0.2), n(0.3)).unwrap(), b(false));
+        assert_eq!(BinaryOperation::ApproxEqual.apply(&n(0.1 + 0.2), &n(0.3)).unwrap(), b(true));
+        assert_eq!(BinaryOperation::ApproxEqual.apply(&n(1e3), &n(1e3 + 1e-3)).unwrap(), b(false));
+        assert_eq!(eq(Value::List(vec![n(1.0), s("a")]), Value::List(vec![n(1.0), s("a")])).unwrap(), b(true));
+        assert_eq!(eq(Value::List(vec![n(1.0)]), Value::List(vec![s("1")])).unwrap(), b(false));
+        assert_eq!(eq(n(1.0), Value::Null).unwrap(), b(false));
+        assert_eq!(eq(Value::Null, Value::Null).unwrap(), b(true));
+        assert!(eq(n(1.0), s("1")).is_err());
+        assert!(eq(b(true), n(1.0)).is_err());
+    }
+
+    #[test]
+    fn test_ordering() {
+        let cmp = |op: &str, left: Value, right: Value| BinaryOperation::from_str(op.to_string()).unwrap().apply(&left, &right);
+        let s = |s: &str| Value::String(s.to_string());
+
+        assert_eq!(cmp("<", s("apple"), s("banana")).unwrap(), Value::Bool(true));
+        assert_eq!(cmp(">=", s("b"), s("b")).unwrap(), Value::Bool(true));
+        assert_eq!(cmp("<=", Value::Number(2.0), Value::Number(1.0)).unwrap(), Value::Bool(false));
+        assert_eq!(cmp("<", Value::Number(f64::NAN), Value::Number(1.0)).unwrap(), Value::Bool(false));
+        assert!(cmp("<", s("1"), Value::Number(2.0)).is_err());
+        assert!(cmp(">", Value::Bool(true), Value::Bool(false)).is_err());
+        assert!(cmp("<", Value::Null, Value::Number(1.0)).is_err());
+    }
+
+    #[test]
+    fn test_unary() {
+        assert_eq!(UnaryOperation::Negate.apply(&Value::Number(2.0)).unwrap(), Value::Number(-2.0));
+        assert!(UnaryOperation::Negate.apply(&Value::Bool(true)).is_err());
+        assert_eq!(UnaryOperation::Not.apply(&Value::Bool(true)).unwrap(), Value::Bool(false));
+        assert_eq!(UnaryOperation::Not.apply(&Value::Null).unwrap(), Value::Bool(true));
+        assert_eq!(UnaryOperation::Increment.apply(&Value::Number(2.0)).unwrap(), Value::Number(3.0));
+        assert!(UnaryOperation::Decrement.apply(&Value::String("a".to_string())).is_err());
+    }
+
+    #[test]
+    fn test_modulo_and_bits() {
+        let apply = |op: &str, left: f64, right: f64| {
+            BinaryOperation::from_str(op.to_string()).unwrap().apply(&Value::Number(left), &Value::Number(right))
+        };
+        assert_eq!(apply("%", 7.0, 3.0).unwrap(), Value::Number(1.0));
+        assert_eq!(apply("%", -7.0, 3.0).unwrap(), Value::Number(2.0));
+        assert_eq!(apply("//", -7.0, 2.0).unwrap(), Value::Number(-4.0));
+        assert!(apply("%", 1.0, 0.0).is_err());
+
+        assert_eq!(apply("&", 0b1100 as f64, 0b1010 as f64).unwrap(), Value::Number(0b1000 as f64));
+        assert_eq!(apply("|", 0b1100 as f64, 0b1010 as f64).unwrap(), Value::Number(0b1110 as f64));
+        assert_eq!(apply("xor", 0b1100 as f64, 0b1010 as f64).unwrap(), Value::Number(0b0110 as f64));
+        assert_eq!(apply("<<", 1.0, 4.0).unwrap(), Value::Number(16.0));
+        assert_eq!(apply(">>", 16.0, 4.0).unwrap(), Value::Number(1.0));
+        assert!(apply("&", 1.5, 1.0).is_err());
+        assert!(apply("<<", 1.0, 64.0).is_err());
+
+        let xor = BinaryOperation::BitXor.apply(&Value::Bool(true), &Value::Bool(true)).unwrap();
+        assert_eq!(xor, Value::Bool(false));
+    }
+
 }
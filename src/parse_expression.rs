@@ -1,139 +1,315 @@
+// chumsky's `Simple` error is large, and it is what `select!` and `try_map` closures return.
+#![allow(clippy::result_large_err)]
+
 use chumsky::prelude::*;
+use chumsky::Stream;
 
-use crate::error::{SML_Result, SML_Error};
-use crate::expression::Expression;
+use crate::expression::{Expression, FormatPart};
+use crate::function::{Function, Registry};
 use crate::value::Value;
-use crate::identifier::Identifier;
+use crate::identifier::{Identifier, PathSegment};
+use crate::lexer::{lex, parse_number, read_escape, Token, Span, ParseError};
 use crate::operation::UnaryOperation;
 use crate::operation::BinaryOperation;
 
 
-fn expr_parser() -> impl Parser<char, Expression, Error = Simple<char>> {
-    let kw_nc = |s: &'static str| { text::keyword(s).map(|()| s.to_string() ) };
+/// A piece of an f-string, before any expressions are parsed.
+enum RawFormatPart {
+    Literal(String),
 
-    let value = recursive(|val|{
-        // TODO: floating point notation
-        let num = text::int(10)
-            .then(just('.').ignore_then(text::digits(10)).or_not())
-            .map(| (sa, sb): (String, Option<String>) |{ 
-                let s = match sb {
-                    Some(sb) => sa + &sb,
-                    None => sa,
-                };
-                Value::Number(s.parse().unwrap())
+    /// Source of an expression, and its offset in the string.
+    Expression(String, usize),
+}
+
+/// Split the contents of an f-string into text and expressions. Braces are written `{{` and `}}`,
+/// and escape sequences in the text are replaced.
+/// On error, returns the message and the offset in the string.
+fn split_format(s: &str) -> Result<Vec<RawFormatPart>, (String, usize)> {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut chars = s.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '{' | '}' if chars.peek().map(|(_, next)| *next) == Some(c) => {
+                chars.next();
+                literal.push(c);
+            },
+            '{' => {
+                // Find the matching brace, allowing for object literals inside.
+                let mut depth = 1;
+                let end = chars.by_ref()
+                    .find(|(_, c)| {
+                        match c {
+                            '{' => depth += 1,
+                            '}' => depth -= 1,
+                            _ => (),
+                        }
+                        depth == 0
+                    })
+                    .map(|(j, _)| j)
+                    .ok_or_else(|| ("Unclosed '{' in f-string".to_string(), i))?;
+                if !literal.is_empty() {
+                    parts.push(RawFormatPart::Literal(std::mem::take(&mut literal)));
+                }
+                parts.push(RawFormatPart::Expression(s[i + 1..end].to_string(), i + 1));
+            },
+            '}' => { return Err(("Unmatched '}' in f-string (write '}}' for a brace)".to_string(), i)); },
+            '\\' => {
+                let c = read_escape(&mut chars.by_ref().map(|(_, c)| c)).map_err(|msg| (msg, i))?;
+                literal.push(c);
+            },
+            c => literal.push(c),
+        }
+    }
+    if !literal.is_empty() {
+        parts.push(RawFormatPart::Literal(literal));
+    }
+    Ok(parts)
+}
+
+/// Parse an f-string token, whose contents start at `offset` in the source.
+fn parse_format(s: &str, offset: usize, registry: &Option<Registry>) -> Result<Expression, Simple<Token>> {
+    let error_at = |msg: String, at: usize| Simple::custom(offset + at..offset + at + 1, msg);
+
+    let mut parts = Vec::new();
+    for part in split_format(s).map_err(|(msg, at)| error_at(msg, at))? {
+        let (src, at) = match part {
+            RawFormatPart::Literal(text) => {
+                parts.push(FormatPart::Literal(text));
+                continue;
+            },
+            RawFormatPart::Expression(src, at) => (src, at),
+        };
+
+        let tokens = lex(&src)
+            .map_err(|errors| error_at(errors[0].message.clone(), at + errors[0].span.start))?
+            .into_iter()
+            .filter(|(t, _)| !matches!(t, Token::Newline | Token::Indent | Token::Dedent))
+            .map(|(t, span)| (t, offset + at + span.start..offset + at + span.end));
+        let eoi = offset + at + src.len();
+        let expression = expr_parser(registry.clone())
+            .then_ignore(end())
+            .parse(Stream::from_iter(eoi..eoi, tokens))
+            .map_err(|errors| {
+                let e = ParseError::from_simple(errors[0].clone());
+                Simple::custom(e.span, e.message)
+            })?;
+        parts.push(FormatPart::Expression(expression));
+    }
+    Ok(Expression::Format(parts))
+}
+
+
+/// Parser for an expression. Function calls are resolved against `registry`, or left unresolved if
+/// there isn't one.
+pub(crate) fn expr_parser(registry: Option<Registry>) -> impl Parser<Token, Expression, Error = Simple<Token>> + Clone {
+    let ctrl = |c: char| just(Token::Ctrl(c));
+    let word = select! { Token::Ident(s) => s };
+
+    let fstr_registry = registry.clone();
+    let fstr = select! { Token::FStr(s) => s }
+        .try_map(move |s, span: Span| parse_format(&s, span.start + 2, &fstr_registry));
+
+    let value = {
+        // Numbers that print differently to how they were written keep their source text.
+        let num = select! { Token::Num(s) => s }
+            .try_map(|s, span| {
+                let n = parse_number(&s).map_err(|msg| Simple::custom(span, msg))?;
+                Ok(match Value::Number(n) {
+                    value if value.to_string() == s => Expression::Value(value),
+                    _ => Expression::Number(n, s),
+                })
             })
-            .padded()
             ;
 
-        let bul = choice((
-                kw_nc("true"),
-                kw_nc("false"),
-            ))
-            .map(|s| Value::Bool(s == "true"))
-            .padded()
+        let bul = select! {
+                Token::Ident(s) if s == "true" => true,
+                Token::Ident(s) if s == "false" => false,
+            }
+            .map(Value::Bool)
             ;
 
-        let str_ = just('"')
-            .ignore_then(none_of("\"").repeated())
-            .then_ignore(just('"'))
-            .collect::<String>()
-            .map(|s: String| Value::String(s))
-            .padded()
-            ;
+        let null = select! { Token::Ident(s) if s == "null" => Value::Null };
 
-        let list = just('[')
-            .ignore_then(val.clone().then_ignore(just(',').or_not()).repeated().at_least(1))
-            .then_ignore(just(']'))
-            .collect::<Vec<Value>>()
-            .map(|v| Value::List(v.into_iter().map(|vi| Box::new(vi)).collect() ))
-            .padded()
+        let str_ = select! { Token::Str(s) => s }
+            .map(Value::String)
             ;
 
-        num.or(bul).or(str_).or(list)
-
-    });
+        num.or(bul.or(null).or(str_).map(Expression::Value))
+    };
 
     recursive(|e| {
 
-        let ident = choice((
-                kw_nc("inputs"),
-                kw_nc("outputs"),
-                kw_nc("globals"),
-            ))
-            .then_ignore(just('.'))
-            .then(text::ident())
-            .map(|(sa, sb): (String, String)| { format!("{sa}.{sb}") })
-            .map(|s: String| { Expression::Identifier(Identifier::from_str(s).unwrap())})
-            .padded()
+        let segment = ctrl('.').ignore_then(word).map(PathSegment::Field)
+            .or(e.clone().delimited_by(ctrl('['), ctrl(']')).map(|e| PathSegment::Index(Box::new(e))));
+
+        let ident = word
+            .then(segment.repeated().at_least(1))
+            .try_map(|(store, path), span: Span| {
+                Identifier::new(&store, path)
+                    .map(Expression::Identifier)
+                    .map_err(|err| Simple::custom(span, err))
+            })
+            ;
+
+        let call = word
+            .then(e.clone()
+                .separated_by(ctrl(','))
+                .allow_trailing()
+                .delimited_by(ctrl('('), ctrl(')')))
+            .try_map(move |(name, args), span: Span| {
+                let Some(registry) = &registry else {
+                    return Ok(Expression::Call(Function::unresolved(&name), args));
+                };
+                let function = registry.get(&name)
+                    .ok_or_else(|| Simple::custom(span.clone(), format!("Unknown function \"{name}\".")))?;
+                function.check_arity(args.len())
+                    .map_err(|msg| Simple::custom(span, msg))?;
+                Ok(Expression::Call(function, args))
+            })
             ;
 
-        let atom = value.map(|v| Expression::Value(v))
+        // A list of literal values is a value itself; otherwise its items are evaluated each time.
+        let literal = |item: &Expression| match item {
+            Expression::Value(value) => Some(value.clone()),
+            _ => None,
+        };
+
+        let list = e.clone()
+            .separated_by(ctrl(','))
+            .allow_trailing()
+            .delimited_by(ctrl('['), ctrl(']'))
+            .map(move |items: Vec<Expression>| match items.iter().map(literal).collect() {
+                Some(values) => Expression::Value(Value::List(values)),
+                None => Expression::List(items),
+            });
+
+        let object = select! { Token::Str(s) => s }
+            .then_ignore(ctrl(':'))
+            .then(e.clone())
+            .separated_by(ctrl(','))
+            .allow_trailing()
+            .delimited_by(ctrl('{'), ctrl('}'))
+            .map(Expression::Object);
+
+        let atom = list
+            .or(object)
+            .or(value)
+            .or(fstr)
+            .or(call)
             .or(ident)
-            .or(e.delimited_by(just('('), just(')')));
-
-        let op = |c| just(c).padded();
-        let op2 = |c| just(c).then(just('=')).padded();
-        let opd = |c: char| just(c).then(just(c)).padded();
-        // Why doesn't this work? :(
-        // let opn = |s: &'static str| text::keyword(s).padded();
-
-        let unary = op('-')
-            .repeated()
-            .then(atom)
-            .foldr(|_op, rhs| Expression::Unary(UnaryOperation::Negate, Box::new(rhs)));
-
-        let curry_binary = |o: BinaryOperation | {
-            |lhs: Expression, rhs: Expression| {
-                Expression::Binary(o, Box::new(lhs), Box::new(rhs))
-            }
+            .or(e.clone().delimited_by(ctrl('('), ctrl(')')))
+            .labelled("an expression")
+            .then(e.clone().delimited_by(ctrl('['), ctrl(']')).repeated())
+            .foldl(|base, index| Expression::Index(Box::new(base), Box::new(index)));
+
+        let op = |s: &'static str| just(Token::Op(s));
+
+        let binary = |lhs: Expression, (op, rhs): (BinaryOperation, Expression)| {
+            Expression::Binary(op, Box::new(lhs), Box::new(rhs))
         };
 
-        let product = unary.clone()
-            .then(choice((
-                    op('*').to(curry_binary(BinaryOperation::Multiply)),
-                    op('/').to(curry_binary(BinaryOperation::Divide)),
-                    op('^').to(curry_binary(BinaryOperation::Power)),
-                ))
+        // `^` binds tighter than unary minus (`-2 ^ 2` is -4), and is right-associative.
+        let unary = recursive(|unary| {
+            let power = atom
+                .then(op("^").to(BinaryOperation::Power).then(unary.clone()).or_not())
+                .map(move |(lhs, rhs)| match rhs {
+                    Some(rhs) => binary(lhs, rhs),
+                    None => lhs,
+                });
+
+            op("-").to(UnaryOperation::Negate)
+                .or(op("!").to(UnaryOperation::Not))
                 .then(unary)
-                .repeated())
-            .foldl(|lhs, (op, rhs)| op(lhs, rhs));
-
-        let sum = product.clone()
-            .then(op('+').to(curry_binary(BinaryOperation::Add))
-                    .or(op('-').to(curry_binary(BinaryOperation::Subtract)))
-                    .then(product)
-                    .repeated())
-            .foldl(|lhs, (op, rhs)| op(lhs, rhs));
-
-        let misc_binary = sum.clone()
-            .then(choice((
-                    opd('&').to(curry_binary(BinaryOperation::And)),
-                    opd('|').to(curry_binary(BinaryOperation::Or)),
-                    op2('=').to(curry_binary(BinaryOperation::Equal)),
-                    op2('!').to(curry_binary(BinaryOperation::NotEqual)),
-                    op2('<').to(curry_binary(BinaryOperation::LessThanOrEqual)),
-                    op2('>').to(curry_binary(BinaryOperation::GreaterThanOrEqual)),
-                    op2('^').to(curry_binary(BinaryOperation::Contains)),
-                    op('=').to(curry_binary(BinaryOperation::Assign)),
-                    op('<').to(curry_binary(BinaryOperation::LessThan)),
-                    op('>').to(curry_binary(BinaryOperation::GreaterThan)),
-                ))
-                .then(sum)
-                .repeated())
-            .foldl(|lhs, (op, rhs)| op(lhs, rhs));
-
-        misc_binary.padded()
-    }).then_ignore(end())
+                .map(|(op, operand)| Expression::Unary(op, Box::new(operand)))
+                .or(power)
+        });
+
+        // Any binary operator binding at `level`; see `BinaryOperation::precedence`.
+        let operator = |level: u8| filter_map(move |span, token: Token| {
+            let op = match &token {
+                Token::Op(s) => BinaryOperation::from_str(s.to_string()).ok(),
+                Token::Ident(s) => BinaryOperation::from_str(s.clone()).ok(),
+                _ => None,
+            };
+            match op {
+                Some(op) if op.precedence() == level => Ok(op),
+                _ => Err(Simple::expected_input_found(span, None, Some(token))),
+            }
+        });
+
+        // Left-associative binary operators, loosest first down to multiplication.
+        let mut binary_expr = unary.boxed();
+        for level in (BinaryOperation::Or.precedence()..=BinaryOperation::Multiply.precedence()).rev() {
+            // Unlike `!`, `not` binds looser than comparisons, as in Python: `not a == b` is
+            // `!(a == b)`.
+            if level == BinaryOperation::And.precedence() {
+                let not = select! { Token::Ident(s) if s == "not" => UnaryOperation::Not };
+                binary_expr = not.repeated()
+                    .then(binary_expr)
+                    .foldr(|op, operand| Expression::Unary(op, Box::new(operand)))
+                    .boxed();
+            }
+            binary_expr = binary_expr.clone()
+                .then(operator(level).then(binary_expr).repeated())
+                .foldl(binary)
+                .boxed();
+        }
+
+        let conditional = recursive(|conditional| {
+            binary_expr.clone()
+                .then(op("?")
+                    .ignore_then(conditional.clone())
+                    .then_ignore(ctrl(':'))
+                    .then(conditional)
+                    .or_not())
+                .map(|(condition, branches)| match branches {
+                    Some((if_true, if_false)) => Expression::Conditional(Box::new(condition), Box::new(if_true), Box::new(if_false)),
+                    None => condition,
+                })
+        });
+
+        // `++`, `--` and assignments need a name to store into, so catch `1 += 1` here rather
+        // than when it runs.
+        let assignable = |target: &Expression, span: Span| match target {
+            Expression::Identifier(_) => Ok(()),
+            _ => Err(Simple::custom(span, "Can only assign to a name, like `outputs.x` or `globals.count`.")),
+        };
+
+        let postfix = conditional
+            .map_with_span(|operand, span| (operand, span))
+            .then(op("++").to(UnaryOperation::Increment)
+                    .or(op("--").to(UnaryOperation::Decrement))
+                    .or_not())
+            .try_map(move |((operand, span), op), _| match op {
+                Some(op) => assignable(&operand, span).map(|_| Expression::Unary(op, Box::new(operand))),
+                None => Ok(operand),
+            });
+
+        // Assignment (`=`, or `+=` and the like) is right-associative, so `a = b = 1` sets both.
+        postfix
+            .map_with_span(|lhs, span| (lhs, span))
+            .then(operator(BinaryOperation::Assign.precedence()).then(e).or_not())
+            .try_map(move |((lhs, span), rhs), _| match rhs {
+                Some(rhs) => assignable(&lhs, span).map(|_| binary(lhs, rhs)),
+                None => Ok(lhs),
+            })
+    })
 }
 
 
-pub fn expr_from_str(s: &str, lineno: usize) -> SML_Result<Expression> {
-    eprintln!("|{}|", s);
-    let parser = expr_parser();
-    match parser.parse(s) {
-        Err(e) => Err(SML_Error::CompilerError(format!("Failed to parse expr on line {lineno}: {e:?}"))),
-        Ok(e) => Ok(e),
-    }
+/// Parse a single expression, outside of any state machine.
+#[cfg(test)]
+pub fn expr_from_str(s: &str) -> crate::error::SML_Result<Expression> {
+    let tokens = lex(s)
+        .map_err(|errors| errors[0].clone().into_diagnostic(s))?
+        .into_iter()
+        .filter(|(t, _)| !matches!(t, Token::Newline | Token::Indent | Token::Dedent));
+    let parser = expr_parser(Some(Registry::new())).then_ignore(end());
+    let eoi = s.len()..s.len();
+    let expr = parser.parse(Stream::from_iter(eoi, tokens))
+        .map_err(|errors| ParseError::from_simple(errors[0].clone()).into_diagnostic(s))?;
+    Ok(expr)
 }
 
 
@@ -145,42 +321,42 @@ mod tests {
     #[test]
     fn test_expr_parse_1() {
         let i = "1";
-        let o = expr_from_str(i, 0).unwrap();
+        let o = expr_from_str(i).unwrap();
         assert!(matches!(o, Expression::Value(Value::Number(_))));
     }
 
     #[test]
     fn test_expr_parse_2() {
         let i = "  true ";
-        let o = expr_from_str(i, 0).unwrap();
+        let o = expr_from_str(i).unwrap();
         assert!(matches!(o, Expression::Value(Value::Bool(_))));
     }
 
     #[test]
     fn test_expr_parse_3() {
         let i = "1 + 1";
-        let o = expr_from_str(i, 0).unwrap();
+        let o = expr_from_str(i).unwrap();
         assert!(matches!(o, Expression::Binary(BinaryOperation::Add, _, _)));
     }
 
     #[test]
     fn test_expr_parse_4() {
         let i = "1 == 1";
-        let o = expr_from_str(i, 0).unwrap();
+        let o = expr_from_str(i).unwrap();
         assert!(matches!(o, Expression::Binary(BinaryOperation::Equal, _, _)));
     }
 
     #[test]
     fn test_expr_parse_5() {
         let i = "1 ^= 1";
-        let o = expr_from_str(i, 0).unwrap();
+        let o = expr_from_str(i).unwrap();
         assert!(matches!(o, Expression::Binary(BinaryOperation::Contains, _, _)));
     }
 
     #[test]
     fn test_expr_parse_identifier_contains_int() {
         let i = "inputs.foo ^= 1";
-        let o = expr_from_str(i, 0).unwrap();
+        let o = expr_from_str(i).unwrap();
         match o {
             Expression::Binary(BinaryOperation::Contains, l, r) => {
                 match (*l, *r) {
@@ -195,7 +371,7 @@ mod tests {
     #[test]
     fn test_expr_parse_string() {
         let i = "inputs.foo = \"ooh thing another bar\"";
-        let o = expr_from_str(i, 0).unwrap();
+        let o = expr_from_str(i).unwrap();
         match o {
             Expression::Binary(BinaryOperation::Assign, l, r) => {
                 match (*l, *r) {
@@ -210,7 +386,7 @@ mod tests {
     #[test]
     fn test_expr_parse_list() {
         let i = "inputs.foo = [1, 2, 3]";
-        let o = expr_from_str(i, 0).unwrap();
+        let o = expr_from_str(i).unwrap();
         match o {
             Expression::Binary(BinaryOperation::Assign, l, r) => {
                 match (*l, *r) {
@@ -225,7 +401,7 @@ mod tests {
     #[test]
     fn test_expr_parse_negate() {
         let i = "-1";
-        let o = expr_from_str(i, 0).unwrap();
+        let o = expr_from_str(i).unwrap();
         match o {
             Expression::Unary(UnaryOperation::Negate, v) => {
                 match *v {
@@ -240,7 +416,7 @@ mod tests {
     #[test]
     fn test_expr_parse_power() {
         let i = "2 ^2";
-        let o = expr_from_str(i, 0).unwrap();
+        let o = expr_from_str(i).unwrap();
         match o {
             Expression::Binary(BinaryOperation::Power, l, r) => {
                 match (*l, *r) {
@@ -252,10 +428,109 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_expr_parse_number_forms() {
+        assert_eq!(eval("0x1F + 0b1010 + 1_000 + 2e-1").unwrap(), Value::Number(1041.2));
+        assert_eq!(expr_from_str("[0x1F, 2.50, 1e3, 7]").unwrap().to_string(), "[0x1F, 2.50, 1e3, 7]");
+    }
+
+    fn eval(s: &str) -> crate::error::SML_Result<Value> {
+        let (i, mut o, mut g, mut l) = (json::object! { "x": 3 }, json::object! { }, json::object! { }, json::object! { });
+        expr_from_str(s).unwrap().evaluate(&i, &mut o, &mut g, &mut l)
+    }
+
+    #[test]
+    fn test_expr_list_items() {
+        let list = |items: Vec<f64>| Value::List(items.into_iter().map(Value::Number).collect());
+        assert_eq!(eval("[-1, 2]").unwrap(), list(vec![-1.0, 2.0]));
+        assert_eq!(eval("[inputs.x, 1]").unwrap(), list(vec![3.0, 1.0]));
+        assert_eq!(eval("[inputs.x * 2 + 1][0]").unwrap(), Value::Number(7.0));
+        assert!(matches!(expr_from_str("[inputs.x]").unwrap(), Expression::List(_)));
+        assert!(expr_from_str("[inputs.x").is_err());
+    }
+
+    #[test]
+    fn test_expr_object_values() {
+        let object = |entries: Vec<(&str, f64)>| Value::Object(entries.into_iter().map(|(k, v)| (k.to_string(), Value::Number(v))).collect());
+        assert_eq!(eval(r#"{"a": -1.5}"#).unwrap(), object(vec![("a", -1.5)]));
+        assert_eq!(eval(r#"{"a": inputs.x, "b": inputs.x * 2}"#).unwrap(), object(vec![("a", 3.0), ("b", 6.0)]));
+        assert_eq!(eval(r#"{"a": {"b": -inputs.x}}["a"]["b"]"#).unwrap(), Value::Number(-3.0));
+        assert!(matches!(expr_from_str(r#"{"a": inputs.x}"#).unwrap(), Expression::Object(_)));
+        assert_eq!(expr_from_str(r#"{"b": 1, "a": 2}"#).unwrap().to_string(), r#"{"b": 1, "a": 2}"#);
+        assert!(expr_from_str(r#"{a: 1}"#).is_err());
+    }
+
+    #[test]
+    fn test_expr_precedence() {
+        for (i, expected) in [
+            // or < and < comparison
+            ("1 == 1 || 2 == 3", Value::Bool(true)),
+            ("false && false || true", Value::Bool(true)),
+            ("true || false && false", Value::Bool(true)),
+            // comparison < bitwise < shift < additive
+            ("6 & 3 == 2", Value::Bool(true)),
+            ("1 | 6 xor 3 & 1", Value::Number(7.0)),
+            ("1 << 1 + 1", Value::Number(4.0)),
+            // additive < multiplicative < unary < power
+            ("1 + 2 * 3", Value::Number(7.0)),
+            ("7 - 4 - 2", Value::Number(1.0)),
+            ("12 / 2 * 3", Value::Number(18.0)),
+            ("-2 ^ 2", Value::Number(-4.0)),
+            ("2 ^ 3 ^ 2", Value::Number(512.0)),
+            ("2 ^ -1", Value::Number(0.5)),
+            // conditional < or
+            ("false || true ? 1 : 2", Value::Number(1.0)),
+            ("false ? 1 : true ? 2 : 3", Value::Number(2.0)),
+        ] {
+            assert_eq!(eval(i).unwrap(), expected, "{i}");
+        }
+
+        // assignment is loosest, and right-associative
+        let o = expr_from_str("outputs.a = outputs.b = 1 + 1").unwrap();
+        let Expression::Binary(BinaryOperation::Assign, _, rhs) = o else { panic!() };
+        assert!(matches!(*rhs, Expression::Binary(BinaryOperation::Assign, _, _)));
+    }
+
+    #[test]
+    fn test_expr_unary_and_update() {
+        let (i, mut o, mut g, mut l) = (json::object! { "x": 3 }, json::object! { }, json::object! { "n": 1 }, json::object! { });
+        let mut eval = |s: &str| expr_from_str(s).unwrap().evaluate(&i, &mut o, &mut g, &mut l);
+
+        assert_eq!(eval("-inputs.x").unwrap(), Value::Number(-3.0));
+        assert_eq!(eval("not inputs.x == 3").unwrap(), Value::Bool(false));
+        assert_eq!(eval("!inputs.x != true").unwrap(), Value::Bool(true));
+        assert!(eval("-true").is_err());
+
+        assert_eq!(eval("globals.n++").unwrap(), Value::Number(2.0));
+        assert_eq!(eval("globals.n += inputs.x").unwrap(), Value::Number(5.0));
+        assert_eq!(eval("globals.n *= 2").unwrap(), Value::Number(10.0));
+        assert_eq!(eval("globals.n--").unwrap(), Value::Number(9.0));
+        assert_eq!(eval("globals.n %= 4").unwrap(), Value::Number(1.0));
+        assert!(eval("inputs.x++").is_err());
+        assert_eq!(g["n"], 1);
+    }
+
+    #[test]
+    fn test_expr_assign_target() {
+        for s in ["1 += 1", "inputs.x + 1 = 2", "(globals.n + 1)++", "globals.n-- = 1"] {
+            let err = expr_from_str(s).unwrap_err().to_string();
+            assert!(err.contains("Can only assign to a name"), "{s}: {err}");
+        }
+        assert!(expr_from_str("globals.a = globals.b = 1").is_ok());
+    }
+
+    #[test]
+    fn test_expr_short_circuit() {
+        assert_eq!(eval("false && inputs.missing").unwrap(), Value::Bool(false));
+        assert_eq!(eval("inputs.x == 3 || inputs.missing").unwrap(), Value::Bool(true));
+        assert!(eval("true && inputs.missing").is_err());
+        assert_eq!(eval("true ? 1 : inputs.missing").unwrap(), Value::Number(1.0));
+    }
+
     #[test]
     fn test_expr_parse_brackets() {
         let i = "2.3 ^(2.5 + 1)";
-        let o = expr_from_str(i, 0).unwrap();
+        let o = expr_from_str(i).unwrap();
         match o {
             Expression::Binary(BinaryOperation::Power, l, r) => {
                 match (*l, *r) {
@@ -267,4 +542,53 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_expr_parse_fstring_errors() {
+        let e = expr_from_str("f\"a {inputs.x\"").unwrap_err();
+        assert!(e.to_string().contains("Unclosed '{' in f-string"));
+        assert!(expr_from_str("f\"a } b\"").is_err());
+
+        // Errors inside the braces point into the string.
+        match expr_from_str("f\"a {inputs.x +}\"").unwrap_err() {
+            crate::error::SML_Error::SyntaxError(d) => assert_eq!(d.span.range.start, 15),
+            e => panic!("{e:?}"),
+        }
+    }
+
+    #[test]
+    fn test_expr_display_round_trip() {
+        for i in [
+            "inputs.x > 1 && globals.y != \"a b\"",
+            "(1 + 2) * 3",
+            "1 - (2 - 3)",
+            "1 + 2 * 3",
+            "-(1 + 2)",
+            "outputs.l = [1, 2.5, true]",
+            "globals.buf = []",
+            "outputs.l = [-1, inputs.x, [globals.a + 1]]",
+            "outputs.o = {\"a\": -1.5, \"b\": [inputs.x], \"c\": {\"d\": globals.d}}",
+            "outputs.x = inputs.a % 2 + inputs.b // 3",
+            "outputs.on = inputs.flags >> 2 & 1 == 1 || inputs.flags xor 255 | 1 > 0",
+            "outputs.x = inputs.x > 0 ? inputs.x : inputs.x < -1 ? -1 : 0",
+            "outputs.x = (inputs.c ? 1 : 2) + 1",
+            "(inputs.a || inputs.b) && inputs.c == (1 < 2)",
+            "2 ^ 3 ^ 2 + (2 ^ 3) ^ 2 + (-2) ^ 2 - -2 ^ 2",
+            "outputs.ok = !inputs.a && !(inputs.b > 1) == -(-inputs.c)",
+            "globals.n += inputs.x * 2",
+            "globals.a //= globals.b = 2",
+            "globals.n++",
+            "inputs.x ~= 0.3 && approx(inputs.y, 1, 0.01)",
+            "outputs.s = \"say \\\"hi\\\"\\n\\\\\" + f\"\\t{inputs.x}\\\"\"",
+            "outputs.x = 1e-9 + 2.5e20 * 1000",
+            "!(inputs.a == 1) || !inputs.b",
+            "globals.counts[inputs.name] = inputs.sensor.readings[-1] + 1",
+            "outputs.o = {\"a\": 1, \"b\": [null, {}]}",
+            "outputs.x = round(clamp(inputs.x, 0, 1) * 10)",
+            "outputs.top = sort(inputs.l)[-1] + [1, 2][0]",
+            "outputs.msg = f\"state {globals.n + 1} of {len(inputs.s)} {{ok}}\"",
+        ] {
+            let o = expr_from_str(i).unwrap();
+            assert_eq!(o.to_string(), i);
+        }
+    }
 }
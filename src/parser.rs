@@ -0,0 +1,176 @@
+// chumsky's `Simple` error is large, and it is what `select!` and `try_map` closures return.
+#![allow(clippy::result_large_err)]
+
+use chumsky::prelude::*;
+use chumsky::Stream;
+
+use crate::function::Registry;
+use crate::ast::{Program, Item, RegionDecl, StateDecl, StateItem, BranchDecl, BranchKind, Line, LineKind, IfArm, ForLoop};
+use crate::lexer::{Token, Span, ParseError};
+use crate::parse_expression::expr_parser;
+
+
+fn kw(s: &'static str) -> impl Parser<Token, Token, Error = Simple<Token>> + Clone {
+    just(Token::Ident(s.to_string()))
+}
+
+/// An indented block of one or more items, following the colon of a header.
+fn block<T, P>(item: P) -> impl Parser<Token, Vec<T>, Error = Simple<Token>> + Clone
+where
+    P: Parser<Token, T, Error = Simple<Token>> + Clone
+{
+    just(Token::Newline)
+        .ignore_then(just(Token::Indent))
+        .ignore_then(item.repeated().at_least(1))
+        .then_ignore(just(Token::Dedent))
+}
+
+/// Recovery parser: skip the rest of a line, and the indented block under it if there is one.
+/// This resynchronises at the next line at the same level, i.e. the next header or statement.
+fn skip_line() -> impl Parser<Token, (), Error = Simple<Token>> + Clone {
+    let nested_block = recursive(|nested_block| {
+        just(Token::Indent)
+            .ignore_then(nested_block.or(none_of([Token::Indent, Token::Dedent]).ignored()).repeated())
+            .then_ignore(just(Token::Dedent))
+            .ignored()
+    });
+
+    none_of([Token::Newline, Token::Indent, Token::Dedent])
+        .repeated()
+        .at_least(1)
+        .then(just(Token::Newline))
+        .then(nested_block.or_not())
+        .ignored()
+}
+
+fn program_parser(registry: Option<Registry>) -> impl Parser<Token, Program, Error = Simple<Token>> {
+    let ctrl = |c: char| just(Token::Ctrl(c));
+    let name = select! { Token::Ident(s) => s }.labelled("a name");
+
+    // A state name, or the path to a nested state, like `Running.Heating`.
+    let path = name.separated_by(ctrl('.')).at_least(1).map(|names| names.join("."));
+
+    let expr = expr_parser(registry.clone());
+
+    let line = recursive(|line| {
+        let simple = choice((
+                kw("end").to(LineKind::End),
+                kw("stay").to(LineKind::Stay),
+                kw("default").to(LineKind::Default),
+                expr.clone().map(LineKind::Expr),
+            ))
+            .map_with_span(|kind, span| Line { kind, span })
+            .then_ignore(just(Token::Newline));
+
+        // An arm of an `if`: its header (condition, and span without the colon) and block.
+        let arm = |header: BoxedParser<'static, Token, Option<_>, Simple<Token>>| header
+            .map_with_span(|condition, span| (condition, span))
+            .then_ignore(ctrl(':'))
+            .then(block(line.clone()))
+            .map(|((condition, span), lines)| IfArm { condition, span, lines });
+
+        let if_ = arm(kw("if").ignore_then(expr.clone()).map(Some).boxed())
+            .then(arm(kw("elif").ignore_then(expr.clone()).map(Some).boxed()).repeated())
+            .then(arm(kw("else").to(None).boxed()).or_not())
+            .map(|((first, elifs), else_)| {
+                LineKind::If(std::iter::once(first).chain(elifs).chain(else_).collect())
+            });
+
+        let for_ = kw("for")
+            .ignore_then(expr.clone())
+            .then_ignore(kw("in"))
+            .then(expr.clone())
+            .map_with_span(|(target, list), span| (target, list, span))
+            .then_ignore(ctrl(':'))
+            .then(block(line.clone()))
+            .map(|((target, list, span), lines)| LineKind::For(ForLoop { target, list, span, lines }));
+
+        // Transition actions are a block (`with:`), or a single expression on the same line.
+        let changeto = kw("changeto")
+            .ignore_then(path.map_with_span(|name, span| (name, span)))
+            .then(kw("with")
+                .ignore_then(choice((
+                    ctrl(':').ignore_then(block(line.clone())),
+                    expr.clone()
+                        .map_with_span(|expression, span| vec![Line { kind: LineKind::Expr(expression), span }])
+                        .then_ignore(just(Token::Newline)),
+                )))
+                .or(just(Token::Newline).to(Vec::new())))
+            .map(|((name, span), actions)| LineKind::ChangeTo(name, span, actions));
+
+        choice((if_, for_, changeto))
+            .map_with_span(|kind, span| Line { kind, span })
+            .or(simple)
+            .recover_with(skip_parser(skip_line().map_with_span(|_, span| Line { kind: LineKind::Error, span })))
+    });
+
+    let head = kw("head")
+        .map_with_span(|_, span| span)
+        .then_ignore(ctrl(':'))
+        .then(block(line.clone()))
+        .map(|(span, lines)| StateItem::Head(lines, span));
+
+    let action = |event, item: fn(Vec<Line>, Span) -> StateItem| kw("on")
+        .then(kw(event))
+        .map_with_span(|_, span| span)
+        .then_ignore(ctrl(':'))
+        .then(block(line.clone()))
+        .map(move |(span, lines)| item(lines, span));
+    let on_enter = action("enter", StateItem::OnEnter);
+    let on_exit = action("exit", StateItem::OnExit);
+
+    let branch = choice((
+            kw("when").ignore_then(expr).map(BranchKind::When),
+            kw("always").to(BranchKind::Always),
+            kw("normally").to(BranchKind::Normally),
+            kw("otherwise").to(BranchKind::Otherwise),
+        ))
+        .map_with_span(|kind, span| (kind, span))
+        .then_ignore(ctrl(':'))
+        .then(block(line.clone()))
+        .map(|((kind, span), lines)| StateItem::Branch(BranchDecl { kind, span, lines }));
+
+    let state = recursive(|state| kw("state")
+        .ignore_then(name.map_with_span(|name, span| (name, span)))
+        .then_ignore(ctrl(':'))
+        .then(block(choice((head, on_enter, on_exit, branch, state.map(StateItem::State)))
+            .recover_with(skip_parser(skip_line().to(StateItem::Error)))))
+        .map(|((name, span), items)| StateDecl { name, span, items }));
+
+    let region = kw("region")
+        .ignore_then(name.map_with_span(|name, span| (name, span)))
+        .then_ignore(ctrl(':'))
+        .then(block(state.clone().map(Some).recover_with(skip_parser(skip_line().to(None)))))
+        .map(|((name, span), states)| RegionDecl { name, span, states });
+
+    let parallel = kw("parallel")
+        .map_with_span(|_, span| span)
+        .then_ignore(ctrl(':'))
+        .then(block(region))
+        .map(|(span, regions)| Item::Parallel(regions, span));
+
+    let default_head = kw("default")
+        .then(kw("head"))
+        .map_with_span(|_, span| span)
+        .then_ignore(ctrl(':'))
+        .then(block(line))
+        .map(|(span, lines)| Item::DefaultHead(lines, span));
+
+    choice((default_head, parallel, state.map(Item::State)))
+        .recover_with(skip_parser(skip_line().to(Item::Error)))
+        .repeated()
+        .then_ignore(end())
+        .map(|items| Program { items })
+}
+
+
+/// Parse a lexed SML source file into its syntax tree. Malformed lines, branches and states are
+/// skipped and reported, so the tree may be returned along with errors.
+///
+/// Function calls are resolved against `registry`, and unknown functions reported. Without a
+/// registry, calls are left unresolved: the tree can be printed, but not run.
+pub fn parse(tokens: Vec<(Token, Span)>, src_len: usize, registry: Option<&Registry>) -> (Option<Program>, Vec<ParseError>) {
+    let (program, errors) = program_parser(registry.cloned())
+        .parse_recovery(Stream::from_iter(src_len..src_len, tokens.into_iter()));
+    (program, errors.into_iter().map(ParseError::from_simple).collect())
+}
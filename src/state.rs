@@ -1,43 +1,98 @@
+use std::ops::Range;
+
 use json::JsonValue;
 
+use crate::diagnostic::RuntimeLocation;
 use crate::error::{SML_Error, SML_Result};
 use crate::expression::Expression;
+use crate::value::Value;
 
 
 #[derive(Clone, Debug)]
 pub enum StateOp {
     Stay,
-    ChangeTo(String),
+    ChangeTo(Transition),
     End
 }
 
 
+/// A change to another state, with any actions to run on the way.
+#[derive(Clone, Debug)]
+pub struct Transition {
+    pub target: String,
+
+    /// Run after the current state's `on exit` block, and before the target's `on enter`.
+    pub actions: Vec<Statement>,
+}
+
+
 impl StateOp {
     pub fn from_str(s: &str) -> SML_Result<Self> {
         if let Some(s) = s.strip_prefix("changeto ") {
-            Ok(Self::ChangeTo(s.to_string()))
+            Ok(Self::ChangeTo(Transition { target: s.to_string(), actions: Vec::new() }))
         }
         else {
             match s {
                 "stay" => Ok(Self::Stay),
                 "end" => Ok(Self::End),
-                s => Err(SML_Error::SyntaxError(format!("Unexpected StateOp: {s:?} (expected \"stay\", \"end\", or \"changeto <state>\".")))
+                s => Err(SML_Error::BadOperation(format!("Unexpected StateOp: {s:?} (expected \"stay\", \"end\", or \"changeto <state>\".")))
             }
         }
     }
 }
 
 
+#[derive(Clone, Debug)]
+pub enum StatementKind {
+    Expr(Expression),
+
+    /// Conditions and their blocks, tried in order, then the block to run if none is true.
+    If(Vec<(Expression, Vec<Statement>)>, Vec<Statement>),
+
+    /// Loop variable, list to loop over, and block.
+    For(Expression, Expression, Vec<Statement>),
+}
+
+/// A statement, along with the line of source it was compiled from (its header, for `if` and `for`).
+#[derive(Clone, Debug)]
+pub struct Statement {
+    pub kind: StatementKind,
+    pub line: usize,
+}
+
+
+#[derive(Clone, Debug)]
+pub struct Branch {
+    pub condition: Expression,
+    pub body: Vec<Statement>,
+    pub state_op: StateOp,
+
+    /// The branch header as written, e.g. `when inputs.x > 1`.
+    pub label: String,
+    pub line: usize,
+    pub span: Range<usize>,
+}
+
+
 #[derive(Clone, Debug)]
 pub struct State {
     name: String,
 
+    /// Where the state's name is in the source.
+    span: Range<usize>,
+
     /// Expressions evaluated when this state is visited
-    head: Vec<Expression>,
+    head: Vec<Statement>,
+
+    /// Statements run when the machine changes into this state (or starts in it).
+    on_enter: Vec<Statement>,
+
+    /// Statements run when the machine changes out of this state, or ends in it.
+    on_exit: Vec<Statement>,
 
     /// List of condition expressions and associated expressions.
     /// When the condition expression is true, the associated body of expressions is run.
-    body: Vec<(Expression, Vec<Expression>, StateOp)>,
+    body: Vec<Branch>,
 
     default_branch: Option<usize>
 }
@@ -45,8 +100,8 @@ pub struct State {
 pub type StateRef = Box<State>;
 
 impl State {
-    pub fn new(name: String, head: Vec<Expression>, body: Vec<(Expression, Vec<Expression>, StateOp)>) -> Self {
-        Self { name, head, body, default_branch: None }
+    pub fn new(name: String, span: Range<usize>, head: Vec<Statement>, on_enter: Vec<Statement>, on_exit: Vec<Statement>, body: Vec<Branch>) -> Self {
+        Self { name, span, head, on_enter, on_exit, body, default_branch: None }
     }
 
     pub fn set_default(&mut self, i: usize) -> SML_Result<()> {
@@ -54,7 +109,8 @@ impl State {
             Err(SML_Error::CompilerError(format!("branch index out of range ({i} > {}) in {}", self.body.len(), self.name)))
         }
         else {
-            Ok(self.default_branch = Some(i))
+            self.default_branch = Some(i);
+            Ok(())
         }
     }
 
@@ -62,46 +118,101 @@ impl State {
         &self.name
     }
 
-    pub fn run(&self, i: &JsonValue, g: &mut JsonValue, default_head: &Vec<Expression>) -> SML_Result<(JsonValue, StateOp)> {
-        self.run_or_advance(i, g, default_head, false)
+    pub fn span(&self) -> Range<usize> {
+        self.span.clone()
     }
-    
-    pub fn run_default(&self, i: &JsonValue, g: &mut JsonValue, default_head: &Vec<Expression>) -> SML_Result<(JsonValue, StateOp)> {
-        self.run_or_advance(i, g, default_head, true)
+
+    pub fn branches(&self) -> &[Branch] {
+        &self.body
+    }
+
+    pub fn default_branch(&self) -> Option<usize> {
+        self.default_branch
+    }
+
+    pub fn run(&self, i: &JsonValue, o: &mut JsonValue, g: &mut JsonValue, l: &mut JsonValue) -> SML_Result<StateOp> {
+        self.run_or_advance(i, o, g, l, false)
     }
     
-    fn run_or_advance(&self, i: &JsonValue, g: &mut JsonValue, default_head: &Vec<Expression>, advance: bool) -> SML_Result<(JsonValue, StateOp)> {
-        let mut o = json::object! { };
+    pub fn run_default(&self, i: &JsonValue, o: &mut JsonValue, g: &mut JsonValue, l: &mut JsonValue) -> SML_Result<StateOp> {
+        self.run_or_advance(i, o, g, l, true)
+    }
 
-        for expr in default_head {
-            expr.evaluate(i, &mut o, g)?;
-        }
+    pub fn enter(&self, i: &JsonValue, o: &mut JsonValue, g: &mut JsonValue, l: &mut JsonValue) -> SML_Result<()> {
+        self.run_block("on enter", &self.on_enter, i, o, g, l)
+    }
+
+    pub fn exit(&self, i: &JsonValue, o: &mut JsonValue, g: &mut JsonValue, l: &mut JsonValue) -> SML_Result<()> {
+        self.run_block("on exit", &self.on_exit, i, o, g, l)
+    }
 
-        for expr in &self.head {
-            expr.evaluate(i, &mut o, g)?;
+    /// Run the actions of a transition out of this state.
+    pub fn transition(&self, transition: &Transition, i: &JsonValue, o: &mut JsonValue, g: &mut JsonValue, l: &mut JsonValue) -> SML_Result<()> {
+        self.run_block(&format!("changeto {}", transition.target), &transition.actions, i, o, g, l)
+    }
+
+    /// Run a block of statements, tagging any error with where it happened.
+    pub(crate) fn run_block(&self, block: &str, statements: &[Statement], i: &JsonValue, o: &mut JsonValue, g: &mut JsonValue, l: &mut JsonValue) -> SML_Result<()> {
+        for statement in statements {
+            let at = |e: SML_Error| e.at(self.location(statement.line, block));
+            match &statement.kind {
+                StatementKind::Expr(expression) => {
+                    expression.evaluate(i, o, g, l).map_err(at)?;
+                },
+                StatementKind::If(conditions, otherwise) => {
+                    let mut body = otherwise;
+                    for (condition, then) in conditions {
+                        if condition.evaluate(i, o, g, l).map_err(at)?.as_bool() {
+                            body = then;
+                            break;
+                        }
+                    }
+                    self.run_block(block, body, i, o, g, l)?;
+                },
+                StatementKind::For(target, list, body) => {
+                    // The list is read once, so the loop is bounded even if the block changes it.
+                    let items = match list.evaluate(i, o, g, l).map_err(at)? {
+                        Value::List(items) => items,
+                        v => {
+                            let message = format!("Can only loop over a list, got {}: {v}.", v.type_name());
+                            return Err(at(SML_Error::BadOperation(message)));
+                        },
+                    };
+                    for item in items {
+                        target.assign(i, o, g, l, &item).map_err(at)?;
+                        self.run_block(block, body, i, o, g, l)?;
+                    }
+                },
+            }
         }
+        Ok(())
+    }
+
+    fn location(&self, line: usize, block: &str) -> RuntimeLocation {
+        RuntimeLocation { line, state: self.name.clone(), block: block.to_string() }
+    }
+    
+    fn run_or_advance(&self, i: &JsonValue, o: &mut JsonValue, g: &mut JsonValue, l: &mut JsonValue, advance: bool) -> SML_Result<StateOp> {
+        self.run_block("head", &self.head, i, o, g, l)?;
 
         let mut state_op = StateOp::Stay;
         if advance {
-            let (_, branch_body, branch_state_op) = &self.body[self.default_branch.unwrap()];
-            for expr in branch_body {
-                expr.evaluate(i, &mut o, g)?;
-            }
-            state_op = branch_state_op.clone();
+            let branch = &self.body[self.default_branch.unwrap()];
+            self.run_block(&branch.label, &branch.body, i, o, g, l)?;
+            state_op = branch.state_op.clone();
         }
         else {
-            for (cond, branch_body, branch_state_op) in &self.body {
-                let v = cond.evaluate(i, &mut o, g)?;
+            for branch in &self.body {
+                let v = branch.condition.evaluate(i, o, g, l)
+                    .map_err(|e| e.at(self.location(branch.line, &branch.label)))?;
                 if v.as_bool() {
-                    for expr in branch_body {
-                        expr.evaluate(i, &mut o, g)?;
-                    }
-                    state_op = branch_state_op.clone();
+                    self.run_block(&branch.label, &branch.body, i, o, g, l)?;
+                    state_op = branch.state_op.clone();
                     break;
                 }
             }
         }
 
-        Ok((o, state_op))
+        Ok(state_op)
     }
 }
@@ -1,27 +1,78 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use serde::{Serialize, de::DeserializeOwned};
 use json::JsonValue;
 
-use crate::expression::Expression;
-use crate::state::{StateOp, StateRef};
+use crate::state::{State, Statement, StateOp, StateRef};
 use crate::error::{SML_Error, SML_Result};
 
 
+/// One of the independent parts of a machine, each in a state of its own. A machine without
+/// `parallel` regions has just one, with no name.
+#[derive(Clone, Debug)]
+struct Region {
+    name: String,
+    initial_state: String,
+    current_state: Option<StateRef>,
+
+    /// Whether the initial state's `on enter` block has been run.
+    entered: bool,
+}
+
+
+/// The state each region (by index) goes to after a run, for those that change state: `None` for
+/// those that end.
+type NextStates = Vec<(usize, Option<StateRef>)>;
+
+
 #[derive(Clone, Debug)]
 pub struct StateMachine {
     globals: JsonValue,
-    default_head: Vec<Expression>,
+    default_head: Vec<Statement>,
+
+    /// The states the machine can be in, by full path (like `Running.Heating`). These have the
+    /// heads and branches of the states they are nested in.
     states: HashMap<String, StateRef>,
-    current_state: Option<StateRef>,
+
+    /// States with other states nested in them, by full path. Only their `on enter` and `on exit`
+    /// blocks are run from here.
+    composites: HashMap<String, StateRef>,
+
+    /// Run in order, each on the same inputs.
+    regions: Vec<Region>,
 }
 
 
 impl StateMachine {
-    pub fn new(default_head: Vec<Expression>, states: HashMap<String, StateRef>, initial_state: StateRef) -> Self {
+    /// `regions` are the name and initial state of each region.
+    pub fn new(default_head: Vec<Statement>, states: HashMap<String, StateRef>, composites: HashMap<String, StateRef>, regions: Vec<(String, StateRef)>) -> Self {
         let globals = json::object! { };
-        let current_state = Some(Box::clone(&initial_state));
-        Self { globals, default_head, states, current_state }
+        let regions = regions.into_iter()
+            .map(|(name, initial_state)| Region {
+                name,
+                initial_state: initial_state.name().clone(),
+                current_state: Some(initial_state),
+                entered: false,
+            })
+            .collect();
+        Self { globals, default_head, states, composites, regions }
+    }
+
+    pub(crate) fn states(&self) -> &HashMap<String, StateRef> {
+        &self.states
+    }
+
+    /// Initial state of each region.
+    pub(crate) fn initial_states(&self) -> Vec<&String> {
+        self.regions.iter().map(|region| &region.initial_state).collect()
+    }
+
+    /// Initial state of the region the state at `path` is in.
+    pub(crate) fn initial_state_of(&self, path: &str) -> &String {
+        let region = self.regions.iter()
+            .find(|region| path.strip_prefix(&region.name).is_some_and(|rest| region.name.is_empty() || rest.starts_with('.')))
+            .unwrap_or(&self.regions[0]);
+        &region.initial_state
     }
 
     pub fn reinit<G: Serialize>(&mut self, g: G) -> SML_Result<()> {
@@ -31,6 +82,14 @@ impl StateMachine {
         Ok(())
     }
 
+    /// Paths of the composite states that the state at `path` is nested in, outermost first.
+    fn ancestors<'a>(&self, path: &'a str) -> Vec<&'a str> {
+        path.match_indices('.')
+            .map(|(i, _)| &path[..i])
+            .filter(|ancestor| self.composites.contains_key(*ancestor))
+            .collect()
+    }
+
     fn get_state(&self, name: &String) -> SML_Result<StateRef> {
         match self.states.get(name) {
             Some(state) => Ok(Box::clone(state)),
@@ -38,11 +97,39 @@ impl StateMachine {
         }
     }
 
-    pub fn current_state(&self) -> Option<String> {
-        match self.current_state.as_ref() {
-            Some(s) => Some(s.name().clone()),
-            None => None
+    /// Run the `on enter` blocks of the composite states `state` is nested in, outermost first,
+    /// then its own. The first `skip` composite states are left out, as the machine is already in
+    /// them.
+    fn enter(&mut self, state: &State, skip: usize, i: &JsonValue, o: &mut JsonValue, l: &mut JsonValue) -> SML_Result<()> {
+        for path in self.ancestors(state.name()).into_iter().skip(skip) {
+            self.composites[path].enter(i, o, &mut self.globals, l)?;
         }
+        state.enter(i, o, &mut self.globals, l)
+    }
+
+    /// Run the `on exit` block of `state`, then those of the composite states it is nested in,
+    /// innermost first. The first `keep` composite states are left out, as the machine stays in
+    /// them.
+    fn exit(&mut self, state: &State, keep: usize, i: &JsonValue, o: &mut JsonValue, l: &mut JsonValue) -> SML_Result<()> {
+        state.exit(i, o, &mut self.globals, l)?;
+        for path in self.ancestors(state.name()).into_iter().skip(keep).rev() {
+            self.composites[path].exit(i, o, &mut self.globals, l)?;
+        }
+        Ok(())
+    }
+
+    /// Full path of the state the machine is in, like `Running.Heating`, or `None` if it has ended.
+    /// For a machine with `parallel` regions, this is the state of the first region; see
+    /// [StateMachine::current_states].
+    pub fn current_state(&self) -> Option<String> {
+        self.regions[0].current_state.as_ref().map(|s| s.name().clone())
+    }
+
+    /// Full path of the state each region is in, in order, or `None` for those that have ended.
+    pub fn current_states(&self) -> Vec<Option<String>> {
+        self.regions.iter()
+            .map(|region| region.current_state.as_ref().map(|s| s.name().clone()))
+            .collect()
     }
 
     pub fn run<I: Serialize, O: DeserializeOwned>(&mut self, i: I) -> SML_Result<Option<O>> {
@@ -54,38 +141,124 @@ impl StateMachine {
     }
 
     fn run_or_advance_state<I: Serialize, O: DeserializeOwned>(&mut self, i: I, advance: bool) -> SML_Result<Option<O>> {
+        if self.regions.iter().all(|region| region.current_state.is_none()) {
+            return Ok(None);
+        }
+
         // Using `DeserializeOwned` instead of `Deserialize` and dealing with lifetime issues
         // https://users.rust-lang.org/t/lifetime-confusion-with-function-parameter-serde-deserialize/76842
-        let (rv, state_op) = match &self.current_state {
-            Some(current_state) => {
-                let i = serde_json::to_string(&i)?;
-                let i = json::parse(&i)?;
-                let state = Box::clone(&current_state);
-                let (o, state_op) = if advance {
-                    (*state).run_default(&i, &mut self.globals, &self.default_head)?
+        let i = serde_json::to_string(&i)?;
+        let i = json::parse(&i)?;
+
+        // A run that fails changes nothing, so that it can be retried.
+        let globals = self.globals.clone();
+        let entered: Vec<bool> = self.regions.iter().map(|region| region.entered).collect();
+        let rv = self.run_regions(&i, advance)
+            .and_then(|(o, next_states)| Ok((serde_json::from_str::<O>(&o.to_string())?, next_states)));
+        let (o, next_states) = match rv {
+            Ok(rv) => rv,
+            Err(e) => {
+                self.globals = globals;
+                for (region, entered) in self.regions.iter_mut().zip(entered) {
+                    region.entered = entered;
                 }
-                else {
-                    (*state).run(&i, &mut self.globals, &self.default_head)?
-                };
-                let o = o.to_string();
-                let o: O = serde_json::from_str(&o)?;
-                (Some(o), state_op)
+                return Err(e);
             },
-            None => {
-                (None, StateOp::Stay)
-            }
         };
 
-        match state_op {
-            StateOp::Stay => {},
-            StateOp::End => { self.current_state = None; },
-            StateOp::ChangeTo(state_name) => {
-                let state = self.get_state(&state_name)?;
-                let _ = self.current_state.insert(state);
-            },
+        for (r, next) in next_states {
+            self.regions[r].current_state = next;
         }
+        Ok(Some(o))
+    }
+
+    /// Run each region still running, returning the merged outputs and where each region that
+    /// changes state goes.
+    fn run_regions(&mut self, i: &JsonValue, advance: bool) -> SML_Result<(JsonValue, NextStates)> {
+        let mut o = json::object! { };
+
+        // Which part of the machine set each output (by path), as two regions may not set the
+        // same one. Outputs of the default head are told apart from those of the region it runs
+        // with.
+        let mut set_by: BTreeMap<Vec<String>, String> = BTreeMap::new();
+        let mut ran_default_head = false;
+        let mut next_states = Vec::new();
+        for r in 0..self.regions.len() {
+            let Some(state) = self.regions[r].current_state.clone() else {
+                continue;
+            };
+            let mut region_o = json::object! { };
+
+            // Locals last for this one run of the region, and are shared by every block it runs.
+            let mut l = json::object! { };
+
+            if !self.regions[r].entered {
+                self.enter(&state, 0, i, &mut region_o, &mut l)?;
+                self.regions[r].entered = true;
+            }
+
+            // The default head runs once, with the first region still running.
+            let mut head_o = None;
+            if !ran_default_head {
+                let before = leaves(&region_o);
+                (*state).run_block("default head", &self.default_head, i, &mut region_o, &mut self.globals, &mut l)?;
+                head_o = Some(leaves(&region_o).into_iter().filter(|leaf| !before.contains(leaf)).collect::<Vec<_>>());
+                ran_default_head = true;
+            }
+
+            let state_op = if advance {
+                (*state).run_default(i, &mut region_o, &mut self.globals, &mut l)?
+            }
+            else {
+                (*state).run(i, &mut region_o, &mut self.globals, &mut l)?
+            };
 
-        Ok(rv)
+            // Leaving a state runs its `on exit` block, then the transition's actions, then the
+            // next state's `on enter`, so their outputs are part of this run's. Composite states
+            // containing both are neither left nor entered.
+            match &state_op {
+                StateOp::Stay => {},
+                StateOp::End => {
+                    self.exit(&state, 0, i, &mut region_o, &mut l)?;
+                    next_states.push((r, None));
+                },
+                StateOp::ChangeTo(transition) => {
+                    let next = self.get_state(&transition.target)?;
+                    let shared = self.ancestors(state.name()).into_iter()
+                        .zip(self.ancestors(next.name()))
+                        .take_while(|(a, b)| a == b)
+                        .count();
+                    self.exit(&state, shared, i, &mut region_o, &mut l)?;
+                    (*state).transition(transition, i, &mut region_o, &mut self.globals, &mut l)?;
+                    self.enter(&next, shared, i, &mut region_o, &mut l)?;
+                    next_states.push((r, Some(next)));
+                },
+            }
+
+            // Without `parallel` regions there is nothing to merge with.
+            if self.regions.len() == 1 {
+                return Ok((region_o, next_states));
+            }
+
+            let mut region_o = leaves(&region_o);
+            let mut outputs = Vec::new();
+            if let Some(head_o) = head_o {
+                region_o.retain(|leaf| !head_o.contains(leaf));
+                outputs.push(("the default head".to_string(), head_o));
+            }
+            outputs.push((format!("region {}", self.regions[r].name), region_o));
+
+            for (name, leaves) in outputs {
+                for (path, value) in leaves {
+                    if let Some((other, at)) = conflict(&set_by, &path) {
+                        return Err(SML_Error::OutputConflict(other.clone(), name, at.join(".")));
+                    }
+                    set_leaf(&mut o, &path, value);
+                    set_by.insert(path, name.clone());
+                }
+            }
+        }
+        Ok((o, next_states))
     }
 
     pub fn globals<G: DeserializeOwned>(&self) -> SML_Result<G> {
@@ -96,10 +269,58 @@ impl StateMachine {
 }
 
 
+/// Every value in the object `o` that isn't itself an object with something in it, by path.
+fn leaves(o: &JsonValue) -> Vec<(Vec<String>, JsonValue)> {
+    fn walk(o: &JsonValue, path: &mut Vec<String>, out: &mut Vec<(Vec<String>, JsonValue)>) {
+        for (key, value) in o.entries() {
+            path.push(key.to_string());
+            if value.is_object() && !value.is_empty() {
+                walk(value, path, out);
+            }
+            else {
+                out.push((path.clone(), value.clone()));
+            }
+            path.pop();
+        }
+    }
+
+    let mut out = Vec::new();
+    walk(o, &mut Vec::new(), &mut out);
+    out
+}
+
+/// Set the value at `path` in the object `o`, making objects along it as needed.
+fn set_leaf(o: &mut JsonValue, path: &[String], value: JsonValue) {
+    let (last, path) = path.split_last().unwrap();
+    let mut o = o;
+    for key in path {
+        if !o[key.as_str()].is_object() {
+            o[key.as_str()] = json::object! { };
+        }
+        o = &mut o[key.as_str()];
+    }
+    o[last.as_str()] = value;
+}
+
+/// What already set the output at `path`, a value it is inside, or one inside it, and the path of
+/// the outer of the two.
+fn conflict<'a>(set_by: &'a BTreeMap<Vec<String>, String>, path: &[String]) -> Option<(&'a String, Vec<String>)> {
+    let outer = (1..=path.len())
+        .find_map(|n| set_by.get(&path[..n]).map(|other| (other, path[..n].to_vec())));
+    let inner = || set_by.range(path.to_vec()..)
+        .next()
+        .filter(|(other_path, _)| other_path.starts_with(path))
+        .map(|(_, other)| (other, path.to_vec()));
+    outer.or_else(inner)
+}
+
+
 #[cfg(test)]
+#[allow(clippy::redundant_static_lifetimes)]
 mod tests {
     use super::StateMachine;
     use crate::compile;
+    use crate::error::{SML_Error, SML_Result};
 
     use serde::{Serialize, Deserialize};
 
@@ -143,4 +364,240 @@ state final:
         let o: OutBar = sm.advance(i).unwrap().unwrap();
         assert_eq!(o.bar, 3u8); // third branch runs
     }
+
+    #[test]
+    fn test_enter_exit() {
+        const SRC: &str = r#"
+state idle:
+    on enter:
+        globals.ticks = 0
+        outputs.entered = "idle"
+    on exit:
+        outputs.left = "idle"
+    when inputs.foo > 0:
+        changeto busy
+    otherwise:
+        globals.ticks++
+state busy:
+    on enter:
+        outputs.entered = "busy"
+    on exit:
+        outputs.left = "busy"
+    when inputs.foo == 2:
+        changeto busy
+    when inputs.foo == 3:
+        end
+"#;
+        let mut sm = compile(SRC).unwrap();
+        let mut run = |foo: u8| -> serde_json::Value { sm.run(InFoo { foo }).unwrap().unwrap() };
+
+        // The initial state is entered on the first run.
+        assert_eq!(run(0), serde_json::json!({ "entered": "idle" }));
+        assert_eq!(run(0), serde_json::json!({ }));
+        assert_eq!(run(1), serde_json::json!({ "left": "idle", "entered": "busy" }));
+        assert_eq!(run(1), serde_json::json!({ }));
+
+        // Changing to the same state leaves and re-enters it.
+        assert_eq!(run(2), serde_json::json!({ "left": "busy", "entered": "busy" }));
+        assert_eq!(run(3), serde_json::json!({ "left": "busy" }));
+    }
+
+    #[test]
+    fn test_transition_actions() {
+        const SRC: &str = r#"
+default head:
+    outputs.order = []
+state idle:
+    on exit:
+        outputs.order += "exit"
+    when inputs.foo == 1:
+        outputs.order += "branch"
+        changeto busy with globals.cycles = 1
+    when inputs.foo == 2:
+        changeto busy with:
+            outputs.order += "transition"
+            globals.cycles = 10
+state busy:
+    on enter:
+        outputs.order += "enter"
+    always:
+        changeto idle
+"#;
+        let mut sm = compile(SRC).unwrap();
+        let mut run = |foo: u8| -> serde_json::Value { sm.run(InFoo { foo }).unwrap().unwrap() };
+
+        assert_eq!(run(1), serde_json::json!({ "order": ["branch", "exit", "enter"] }));
+        assert_eq!(run(0), serde_json::json!({ "order": [] }));
+        assert_eq!(run(2), serde_json::json!({ "order": ["exit", "transition", "enter"] }));
+        let g: serde_json::Value = sm.globals().unwrap();
+        assert_eq!(g, serde_json::json!({ "cycles": 10 }));
+    }
+
+    #[test]
+    fn test_nested_states() {
+        const SRC: &str = r#"
+state Running:
+    on enter:
+        globals.log += "enter Running"
+    on exit:
+        globals.log += "exit Running"
+    when inputs.foo == 9:
+        changeto Fault
+    state Idle:
+        when inputs.foo == 1:
+            changeto Heating
+    state Heating:
+        on enter:
+            globals.log += "enter Heating"
+        on exit:
+            globals.log += "exit Heating"
+        when inputs.foo == 2:
+            changeto Running
+state Fault:
+    when inputs.foo == 0:
+        changeto Running.Heating
+"#;
+        let mut sm = compile(SRC).unwrap();
+        let mut run = |foo: u8| -> serde_json::Value {
+            sm.reinit(serde_json::json!({ "log": [] })).unwrap();
+            let _: serde_json::Value = sm.run(InFoo { foo }).unwrap().unwrap();
+            let g: serde_json::Value = sm.globals().unwrap();
+            serde_json::json!([sm.current_state(), g["log"]])
+        };
+
+        assert_eq!(run(0), serde_json::json!(["Running.Idle", ["enter Running"]]));
+        assert_eq!(run(1), serde_json::json!(["Running.Heating", ["enter Heating"]]));
+
+        // A composite state is entered at its first nested state, without leaving it.
+        assert_eq!(run(2), serde_json::json!(["Running.Idle", ["exit Heating"]]));
+
+        // Branches of the parent are inherited, and run first.
+        assert_eq!(run(9), serde_json::json!(["Fault", ["exit Running"]]));
+        assert_eq!(run(0), serde_json::json!(["Running.Heating", ["enter Running", "enter Heating"]]));
+        assert_eq!(run(9), serde_json::json!(["Fault", ["exit Heating", "exit Running"]]));
+    }
+
+    #[test]
+    fn test_parallel_regions() {
+        const SRC: &str = r#"
+default head:
+    globals.runs = globals.runs + 1
+parallel:
+    region Heater:
+        state Off:
+            when inputs.foo > 1:
+                changeto On
+        state On:
+            always:
+                outputs.heat = true
+                end
+    region Fan:
+        state Slow:
+            on enter:
+                outputs.fan = "slow"
+            when inputs.foo == 3:
+                changeto Fast
+        state Fast:
+            always:
+                outputs.heat = false
+"#;
+        let mut sm = compile(SRC).unwrap();
+        sm.reinit(serde_json::json!({ "runs": 0 })).unwrap();
+        let mut run = |foo: u8| -> SML_Result<Option<serde_json::Value>> { sm.run(InFoo { foo }) };
+
+        assert_eq!(run(0).unwrap(), Some(serde_json::json!({ "fan": "slow" })));
+        assert_eq!(run(2).unwrap(), Some(serde_json::json!({ })));
+        assert_eq!(run(3).unwrap(), Some(serde_json::json!({ "heat": true })));
+        assert_eq!(sm.current_states(), vec![None, Some("Fan.Fast".to_string())]);
+
+        // The default head runs once per run, whichever regions are still running.
+        let g: serde_json::Value = sm.globals().unwrap();
+        assert_eq!(g, serde_json::json!({ "runs": 3 }));
+    }
+
+    #[test]
+    fn test_parallel_output_conflict() {
+        const SRC: &str = r#"
+parallel:
+    region A:
+        state A1:
+            always:
+                outputs.x = 1
+    region B:
+        state B1:
+            always:
+                outputs.x = 2
+"#;
+        let mut sm = compile(SRC).unwrap();
+        let rv: SML_Result<Option<serde_json::Value>> = sm.run(InFoo { foo: 0 });
+        match rv {
+            Err(e @ SML_Error::OutputConflict(..)) => assert_eq!(e.to_string(), "Both region A and region B set outputs.x."),
+            _ => panic!(),
+        }
+        assert_eq!(sm.current_states(), vec![Some("A.A1".to_string()), Some("B.B1".to_string())]);
+    }
+
+    #[test]
+    fn test_parallel_nested_outputs() {
+        const SRC: &str = r#"
+default head:
+    outputs.s.n = inputs.foo
+parallel:
+    region A:
+        state A1:
+            always:
+                outputs.s.a = 1
+                outputs.t = {"a": {"b": 1}}
+    region B:
+        state B1:
+            when inputs.foo == 0:
+                outputs.s.b = 2
+                outputs.t.a.c = 2
+            when inputs.foo == 1:
+                outputs.t.a = 2
+            when inputs.foo == 2:
+                outputs.s.n = 3
+"#;
+        let mut sm = compile(SRC).unwrap();
+        let mut run = |foo: u8| -> SML_Result<Option<serde_json::Value>> { sm.run(InFoo { foo }) };
+
+        // Outputs are merged value by value, into the same objects.
+        let o = run(0).unwrap();
+        assert_eq!(o, Some(serde_json::json!({ "s": { "n": 0, "a": 1, "b": 2 }, "t": { "a": { "b": 1, "c": 2 } } })));
+
+        // Replacing an object another region set a value in conflicts, as does changing an output
+        // of the default head.
+        let conflict = |rv: SML_Result<Option<serde_json::Value>>| rv.unwrap_err().to_string();
+        assert_eq!(conflict(run(1)), "Both region A and region B set outputs.t.a.");
+        assert_eq!(conflict(run(2)), "Both the default head and region B set outputs.s.n.");
+    }
+
+    #[test]
+    fn test_parallel_conflict_rolls_back() {
+        const SRC: &str = r#"
+default head:
+    globals.runs += 1
+parallel:
+    region A:
+        state A1:
+            on enter:
+                globals.entered += 1
+            always:
+                outputs.x = 1
+    region B:
+        state B1:
+            when inputs.foo == 1:
+                outputs.x = 2
+"#;
+        let mut sm = compile(SRC).unwrap();
+        sm.reinit(serde_json::json!({ "runs": 0, "entered": 0 })).unwrap();
+        assert!(sm.run::<_, serde_json::Value>(InFoo { foo: 1 }).is_err());
+        let g: serde_json::Value = sm.globals().unwrap();
+        assert_eq!(g, serde_json::json!({ "runs": 0, "entered": 0 }));
+
+        // The failed run left no trace, so the initial state is still entered on the next.
+        sm.run::<_, serde_json::Value>(InFoo { foo: 0 }).unwrap();
+        let g: serde_json::Value = sm.globals().unwrap();
+        assert_eq!(g, serde_json::json!({ "runs": 1, "entered": 1 }));
+    }
 }
@@ -1,6 +1,10 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
 use json::JsonValue;
 
 use crate::error::{SML_Result, SML_Error};
+use crate::lexer::escape;
 
 
 #[derive(Debug, Clone)]
@@ -8,7 +12,16 @@ pub enum Value {
     String(String),
     Number(f64),
     Bool(bool),
-    List(Vec<Box<Value>>),
+    List(Vec<Value>),
+    Object(BTreeMap<String, Value>),
+    Null,
+}
+
+/// Position of `index` in a list of length `len`, counting from the end if negative. `None` if
+/// out of range.
+pub(crate) fn resolve_index(index: i64, len: usize) -> Option<usize> {
+    let resolved = if index < 0 { len as i64 + index } else { index };
+    (0..len as i64).contains(&resolved).then_some(resolved as usize)
 }
 
 impl Value {
@@ -26,12 +39,22 @@ impl Value {
         else if json.is_array() {
             let mut list = Vec::new();
             for item in json.members() {
-                list.push(Box::new(Value::new(item)?));
+                list.push(Value::new(item)?);
             }
             Ok(Self::List(list))
         }
+        else if json.is_object() {
+            let mut object = BTreeMap::new();
+            for (key, item) in json.entries() {
+                object.insert(key.to_string(), Value::new(item)?);
+            }
+            Ok(Self::Object(object))
+        }
+        else if json.is_null() {
+            Ok(Self::Null)
+        }
         else {
-            Err(SML_Error::JsonFormatError("Value expects a json number, string, array, or boolean. Got null, object, or empty.".to_string()))
+            Err(SML_Error::JsonFormatError(format!("Value expects a json number, string, array, boolean, object, or null. Got {json}.")))
         }
     }
 
@@ -41,6 +64,45 @@ impl Value {
             Self::Number(v) => *v != 0.0,
             Self::String(v) => !v.is_empty(),
             Self::List(v) => !v.is_empty(),
+            Self::Object(v) => !v.is_empty(),
+            Self::Null => false,
+        }
+    }
+
+    /// Name of the value's type, for error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::String(_) => "string",
+            Self::Number(_) => "number",
+            Self::Bool(_) => "bool",
+            Self::List(_) => "list",
+            Self::Object(_) => "object",
+            Self::Null => "null",
+        }
+    }
+
+    /// The value as text: strings as they are, anything else as it is displayed.
+    pub fn as_string(&self) -> String {
+        match self {
+            Self::String(s) => s.clone(),
+            v => v.to_string(),
+        }
+    }
+
+    /// Item of a list by (whole number) index, or value of an object by key.
+    pub fn index(&self, key: &Value) -> SML_Result<Value> {
+        match (self, key) {
+            (Self::List(items), Self::Number(n)) if n.fract() == 0.0 => {
+                resolve_index(*n as i64, items.len())
+                    .map(|i| items[i].clone())
+                    .ok_or_else(|| SML_Error::BadOperation(format!("Index {n} out of range for list of length {}.", items.len())))
+            },
+            (Self::Object(entries), Self::String(key)) => {
+                entries.get(key)
+                    .cloned()
+                    .ok_or_else(|| SML_Error::BadOperation(format!("No key \"{key}\" in object.")))
+            },
+            (value, key) => Err(SML_Error::BadOperation(format!("Cannot index {value} with {key}; lists are indexed by whole numbers and objects by strings."))),
         }
     }
 
@@ -51,7 +113,15 @@ impl Value {
             Self::Number(n) => JsonValue::Number((*n).into()),
             Self::List(l) => {
                 JsonValue::Array(l.iter().map(|v| v.as_json()).collect())
-            }
+            },
+            Self::Object(m) => {
+                let mut object = json::object::Object::new();
+                for (key, value) in m {
+                    object.insert(key, value.as_json());
+                }
+                JsonValue::Object(object)
+            },
+            Self::Null => JsonValue::Null,
         }
     }
 }
@@ -64,7 +134,25 @@ impl PartialEq for Value {
             (Self::Bool(b1), Self::Bool(b2)) => b1 == b2,
             (Self::Number(n1), Self::Number(n2)) => n1 == n2,
             (Self::List(l1), Self::List(l2)) => l1 == l2,
+            (Self::Object(m1), Self::Object(m2)) => m1 == m2,
+            (Self::Null, Self::Null) => true,
             _ => false
         }
     }
 }
+
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::String(s) => write!(f, "\"{}\"", escape(s)),
+            // Very large and small numbers in scientific notation, as `1e-9` rather than `0.000000001`.
+            Self::Number(n) if *n != 0.0 && (n.abs() >= 1e16 || n.abs() < 1e-6) => write!(f, "{n:e}"),
+            Self::Number(n) => write!(f, "{n}"),
+            Self::Bool(b) => write!(f, "{b}"),
+            Self::List(l) => write!(f, "[{}]", l.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", ")),
+            Self::Object(m) => write!(f, "{{{}}}", m.iter().map(|(k, v)| format!("\"{}\": {v}", escape(k))).collect::<Vec<_>>().join(", ")),
+            Self::Null => write!(f, "null"),
+        }
+    }
+}